@@ -20,6 +20,8 @@ type ClapErrorKind = clap::error::ErrorKind;
 pub struct FuzzyParser {
     known_values: Vec<&'static str>,
     inferable_prefix: Option<&'static str>,
+    allow_abbreviations: bool,
+    strict: bool,
 }
 
 impl FuzzyParser {
@@ -30,23 +32,44 @@ impl FuzzyParser {
         Self {
             known_values: known_values.into(),
             inferable_prefix,
+            allow_abbreviations: false,
+            strict: false,
         }
     }
 
+    /// Also accept any unambiguous case-insensitive prefix of a known value, erroring when the
+    /// prefix is ambiguous. Only affects parsers without an inferable prefix, which already infer
+    /// values from their prefixes.
+    pub fn allow_abbreviations(mut self) -> Self {
+        self.allow_abbreviations = true;
+        self
+    }
+
+    /// Reject any value which does not match a known value, rather than accepting it as-is, for
+    /// closed sets of values. Only affects parsers without an inferable prefix, which already
+    /// reject values outside of their known values and prefix "namespace".
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// The known value most similar to `value`, if any is similar enough to be worth suggesting.
+    fn closest_match(&self, value: &str) -> Option<&'static str> {
+        let value = value.to_ascii_lowercase();
+        self.known_values
+            .iter()
+            .map(|known| (edit_distance(&known.to_ascii_lowercase(), &value), *known))
+            .filter(|(distance, known)| distance * 3 <= known.len())
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, known)| known)
+    }
+
     fn error(
         &self,
         cmd: &clap::Command,
         arg: Option<&clap::Arg>,
         val: impl Into<String>,
     ) -> ClapError {
-        let mut err = ClapError::new(ClapErrorKind::InvalidValue).with_cmd(cmd);
-        if let Some(arg) = arg {
-            err.insert(
-                ContextKind::InvalidArg,
-                ContextValue::String(arg.to_string()),
-            );
-        }
-        err.insert(ContextKind::InvalidValue, ContextValue::String(val.into()));
         // We mention the inferable_prefix here to make it clear that there is a "namespace" where
         // any string is legal, alongside the incomplete set of known values. We do not include
         // this in the possible_values proper as we it would confuse the autocomplete generation.
@@ -55,14 +78,40 @@ impl FuzzyParser {
             valid_values.push(format!("{prefix}*"));
         }
         valid_values.extend(self.known_values.iter().copied().map(String::from));
+        let val = val.into();
+        let suggestion = self.closest_match(&val);
+        let mut err = Self::error_with_valid_values(cmd, arg, val, valid_values);
+        if let Some(suggestion) = suggestion {
+            err.insert(
+                ContextKind::SuggestedValue,
+                ContextValue::String(suggestion.to_string()),
+            );
+        }
+        err
+    }
+
+    fn error_with_valid_values(
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        val: impl Into<String>,
+        valid_values: Vec<String>,
+    ) -> ClapError {
+        let mut err = ClapError::new(ClapErrorKind::InvalidValue).with_cmd(cmd);
+        if let Some(arg) = arg {
+            err.insert(
+                ContextKind::InvalidArg,
+                ContextValue::String(arg.to_string()),
+            );
+        }
+        err.insert(ContextKind::InvalidValue, ContextValue::String(val.into()));
         err.insert(ContextKind::ValidValue, ContextValue::Strings(valid_values));
         err
     }
 
     fn parse_ref_without_inferable_prefix(
         &self,
-        _cmd: &clap::Command,
-        _arg: Option<&clap::Arg>,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
         value: &str,
     ) -> Result<String, ClapError> {
         let matching = self
@@ -70,10 +119,34 @@ impl FuzzyParser {
             .iter()
             .filter(|s| s.eq_ignore_ascii_case(value))
             .collect::<Vec<_>>();
-        match matching[..] {
-            [unique] => Ok(unique.to_string()),
-            _ => Ok(value.to_string()),
+        if let [unique] = matching[..] {
+            return Ok(unique.to_string());
+        }
+        if self.allow_abbreviations && !value.is_empty() {
+            let value_lower = value.to_ascii_lowercase();
+            let abbreviated = self
+                .known_values
+                .iter()
+                .filter(|s| s.to_ascii_lowercase().starts_with(&value_lower))
+                .collect::<Vec<_>>();
+            match abbreviated[..] {
+                [] => {}
+                [unique] => return Ok(unique.to_string()),
+                _ => {
+                    let candidates = abbreviated.into_iter().copied().map(String::from);
+                    return Err(Self::error_with_valid_values(
+                        cmd,
+                        arg,
+                        value,
+                        candidates.collect(),
+                    ));
+                }
+            }
+        }
+        if self.strict {
+            return Err(self.error(cmd, arg, value));
         }
+        Ok(value.to_string())
     }
 
     fn parse_ref_with_inferable_prefix(
@@ -125,6 +198,22 @@ impl TypedValueParser for FuzzyParser {
     }
 }
 
+/// The Levenshtein distance between `a` and `b`, in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
 /// A newtype around a bool that implements `AsRef<OsStr>`, so it can be used
 /// with `#[derive(ArgsToVec)]`.
 #[derive(Clone, Copy)]
@@ -194,3 +283,69 @@ impl OverridingVec for clap::Arg {
         self.action(ArgAction::Set).value_delimiter(',')
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(parser: &FuzzyParser, value: &str) -> Result<String, ClapError> {
+        parser.parse_ref(&clap::Command::new("test"), None, OsStr::new(value))
+    }
+
+    fn suggestion(err: &ClapError) -> Option<String> {
+        match err.get(ContextKind::SuggestedValue) {
+            Some(ContextValue::String(s)) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn lenient_accepts_unknown_values() {
+        let parser = FuzzyParser::new(["Release", "Debug"], None);
+        assert_eq!(parse(&parser, "debug").unwrap(), "Debug");
+        assert_eq!(parse(&parser, "Custom").unwrap(), "Custom");
+    }
+
+    #[test]
+    fn strict_accepts_known_values() {
+        let parser = FuzzyParser::new(["Release", "Debug"], None).strict();
+        assert_eq!(parse(&parser, "release").unwrap(), "Release");
+        assert_eq!(parse(&parser, "Debug").unwrap(), "Debug");
+    }
+
+    #[test]
+    fn strict_rejects_unknown_values() {
+        let parser = FuzzyParser::new(["Release", "Debug"], None).strict();
+        let err = parse(&parser, "Custom").unwrap_err();
+        assert_eq!(err.kind(), ClapErrorKind::InvalidValue);
+        assert_eq!(suggestion(&err), None);
+    }
+
+    #[test]
+    fn strict_suggests_closest_match() {
+        let parser = FuzzyParser::new(["Release", "Debug", "RelWithDebInfo"], None).strict();
+        let err = parse(&parser, "Relase").unwrap_err();
+        assert_eq!(suggestion(&err).as_deref(), Some("Release"));
+        let err = parse(&parser, "debg").unwrap_err();
+        assert_eq!(suggestion(&err).as_deref(), Some("Debug"));
+    }
+
+    #[test]
+    fn strict_with_abbreviations() {
+        let parser = FuzzyParser::new(["Release", "RelWithDebInfo", "Debug"], None)
+            .allow_abbreviations()
+            .strict();
+        assert_eq!(parse(&parser, "d").unwrap(), "Debug");
+        assert_eq!(parse(&parser, "relw").unwrap(), "RelWithDebInfo");
+        assert!(parse(&parser, "rel").is_err());
+        assert!(parse(&parser, "x").is_err());
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("release", "relase"), 1);
+    }
+}
@@ -6,7 +6,7 @@ use proc_macro2::TokenStream;
 use quote::{quote, quote_spanned};
 use syn::ext::IdentExt;
 use syn::spanned::Spanned;
-use syn::{Data, DeriveInput, Fields, parse_macro_input};
+use syn::{Data, DeriveInput, Fields, Type, parse_macro_input};
 
 /// Implement ArgsToVec on an Args struct.
 ///
@@ -16,7 +16,8 @@ use syn::{Data, DeriveInput, Fields, parse_macro_input};
 ///
 /// * Each field of the Args struct must be an `#[arg(...)]`
 /// * Each arg must have a default `long` attribute
-/// * Each arg must be of type `Option<T> where T: AsRef<OsStr>`
+/// * Each arg must be of type `Option<T> where T: AsRef<OsStr>`, or `u8` for an
+///   `ArgAction::Count` flag (which is repeated as many times as it was counted)
 #[proc_macro_derive(ArgsToVec)]
 pub fn derive_args_to_vec(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
@@ -38,6 +39,11 @@ pub fn derive_args_to_vec(input: proc_macro::TokenStream) -> proc_macro::TokenSt
     proc_macro::TokenStream::from(expanded)
 }
 
+/// Whether a field is a `u8`, as used by clap for `ArgAction::Count`.
+fn is_count(ty: &Type) -> bool {
+    matches!(ty, Type::Path(p) if p.qself.is_none() && p.path.is_ident("u8"))
+}
+
 fn data_to_vec(data: &Data) -> TokenStream {
     let mut pushes = vec![];
     match *data {
@@ -48,6 +54,15 @@ fn data_to_vec(data: &Data) -> TokenStream {
                     let field_name = field.ident.as_ref().unwrap();
                     let s = field_name.unraw().to_string();
                     let arg_name = s.to_kebab_case();
+                    if is_count(&field.ty) {
+                        let flag = format!("--{arg_name}");
+                        pushes.push(quote_spanned!(span=> {
+                            for _ in 0..self.#field_name {
+                                v.push(OsString::from(#flag));
+                            }
+                        }));
+                        continue;
+                    }
                     let flag = format!("--{arg_name}=");
                     pushes.push(quote_spanned!(span=> {
                         if let Some(ref x) = self.#field_name {
@@ -4,8 +4,49 @@ use std::io::Write;
 
 include!("src/cli.rs");
 
+/// Check that each list of known values in values/ is sorted (case-insensitively) and free of
+/// duplicates, so that fuzzy matching, completions, and help output remain deterministic.
+fn check_values() -> std::io::Result<()> {
+    for entry in std::fs::read_dir("values")? {
+        let path = entry?.path();
+        if path.extension() != Some("in".as_ref()) {
+            continue;
+        }
+        let contents = std::fs::read_to_string(&path)?;
+        let values = contents
+            .trim()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .split(',')
+            .map(|v| v.trim().trim_matches('"'))
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>();
+        let mut normalized = values.clone();
+        normalized.sort_by_cached_key(|v| (v.to_lowercase(), v.to_string()));
+        normalized.dedup();
+        if values != normalized {
+            panic!("{path:?} must be sorted and deduplicated, i.e.:\n{normalized:?}");
+        }
+    }
+    Ok(())
+}
+
 fn main() -> std::io::Result<()> {
-    let outdir = std::path::PathBuf::from("gen/");
+    // Declaring any rerun-if directive disables the default of rerunning on any change to the
+    // package, so every input must be listed.
+    for path in ["build.rs", "src/cli.rs", "values"] {
+        println!("cargo:rerun-if-changed={path}");
+    }
+    for var in ["CM_GEN_DIR", "CM_SKIP_README"] {
+        println!("cargo:rerun-if-env-changed={var}");
+    }
+
+    check_values()?;
+
+    // Packagers can redirect the completions and man pages with CM_GEN_DIR.
+    let outdir = std::env::var_os("CM_GEN_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map_or_else(|| std::path::PathBuf::from("gen/"), Into::into);
 
     std::fs::create_dir_all(&outdir)?;
 
@@ -14,21 +55,30 @@ fn main() -> std::io::Result<()> {
     for &shell in clap_complete::Shell::value_variants() {
         clap_complete::generate_to(shell, &mut cmd, "cm", &outdir)?;
     }
+    // Nushell is not one of clap_complete's built-in shells.
+    let nushell =
+        clap_complete::generate_to(clap_complete_nushell::Nushell, &mut cmd, "cm", &outdir)?;
+    if std::fs::metadata(&nushell)?.len() == 0 {
+        panic!("{nushell:?} must not be empty");
+    }
 
     let mut buffer: Vec<u8> = Vec::default();
-    let man = clap_mangen::Man::new(cmd.clone());
-    man.render(&mut buffer)?;
+    render_man(cmd.clone(), &mut buffer)?;
     let cmd_name = cmd.get_name();
     std::fs::write(outdir.join(format!("{cmd_name}.1")), &buffer)?;
 
     for subcmd in cmd.get_subcommands() {
         buffer.clear();
-        let man = clap_mangen::Man::new(subcmd.clone());
-        man.render(&mut buffer)?;
+        render_man(subcmd.clone(), &mut buffer)?;
         let subcmd_name = subcmd.get_name();
         std::fs::write(outdir.join(format!("{cmd_name}-{subcmd_name}.1")), &buffer)?;
     }
 
+    // Packaging builds may not be able to (or want to) write into the source tree.
+    if std::env::var_os("CM_SKIP_README").is_some_and(|v| !v.is_empty()) {
+        return Ok(());
+    }
+
     let long_help = format!("{}", cmd.render_long_help());
     let mut parts = long_help.split("Usage: ");
     let purpose = parts.next().unwrap();
@@ -1,32 +1,32 @@
 // Copyright © 2026 Advanced Micro Devices, Inc. All rights reserved.
 // SPDX-License-Identifier: MIT
 
-use crate::cli::Globals;
-use anyhow::{Context, Result};
+use crate::cli::{Cli, Globals};
+use anyhow::{bail, Context, Result};
 use applause::ArgsToVec;
-use clap::{Parser, Subcommand};
+use clap::{Arg, Command, CommandFactory, Parser, Subcommand};
+use log::debug;
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
-use std::io::{BufRead, BufReader, Lines};
+use std::fs;
 use std::path::PathBuf;
 
 #[derive(Default, Debug)]
 struct Config {
-    inner: Option<ConfigInner>,
+    lines: Vec<String>,
 }
 
+/// The config file section defining custom subcommand aliases, as `<alias> = <subcommand>` lines.
+const ALIASES_SECTION: &str = "aliases";
+
 impl Config {
     fn from_path<P: Into<PathBuf>>(p: P) -> Result<Config> {
         let path = p.into();
+        debug!("trying config file {path:?}");
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("could not open {path:?}"))?;
         Ok(Config {
-            inner: Some(ConfigInner {
-                lines: BufReader::new(
-                    File::open(&path).with_context(|| format!("could not open {path:?}"))?,
-                )
-                .lines(),
-                section: "".into(),
-            }),
+            lines: contents.lines().map(Into::into).collect(),
         })
     }
 
@@ -49,39 +49,143 @@ impl Config {
         })
     }
 
-    fn slurp_into(mut self, subcommand_prefix: &OsStr, out: &mut Vec<OsString>) -> Result<()> {
-        let inner = match &mut self.inner {
-            Some(ref mut i) => i,
-            _ => return Ok(()),
+    /// The lines of the config file, paired with the name of the section each is in (resolved
+    /// where possible, or empty for the global section), skipping comments and blank lines.
+    fn sectioned_lines<'a>(&'a self, cmd: &'a Command) -> impl Iterator<Item = (String, &'a str)> {
+        let mut section = String::new();
+        self.lines.iter().filter_map(move |line| {
+            if line.trim_start().starts_with('#') || line.trim().is_empty() {
+                return None;
+            }
+            let is_alias = section == ALIASES_SECTION && line.contains('=');
+            if !line.starts_with('-') && !is_alias {
+                section =
+                    resolve_subcommand(cmd, line.trim()).unwrap_or_else(|| line.trim().into());
+                return None;
+            }
+            Some((section.clone(), line.as_str()))
+        })
+    }
+
+    /// Expand `subcommand` if it is a custom alias from the aliases section. The built-in
+    /// subcommand names and aliases always take precedence.
+    fn expand_alias(&self, cmd: &Command, subcommand: OsString) -> Result<OsString> {
+        let Some(name) = subcommand.to_str() else {
+            return Ok(subcommand);
         };
-        while let Some(line) = inner.lines.next() {
-            let line = line.context("could not read next line from config file")?;
-            if line.starts_with('-') {
-                if inner.in_section(subcommand_prefix) {
-                    out.push(line.into());
-                }
-            } else if line.trim_start().starts_with('#') || line.trim().is_empty() {
+        if cmd.find_subcommand(name).is_some() {
+            return Ok(subcommand);
+        }
+        for (section, line) in self.sectioned_lines(cmd) {
+            if section != ALIASES_SECTION {
                 continue;
-            } else {
-                inner.section = line;
+            }
+            let Some((alias, target)) = line.split_once('=') else {
+                continue;
+            };
+            if alias.trim() == name {
+                let target = target.trim();
+                let Some(resolved) = resolve_subcommand(cmd, target) else {
+                    bail!("the alias {name:?} in the config file is for unknown subcommand {target:?}");
+                };
+                debug!("expanding alias {name:?} to {resolved:?}");
+                return Ok(resolved.into());
+            }
+        }
+        Ok(subcommand)
+    }
+
+    fn slurp_into(self, cmd: &Command, subcommand: &OsStr, out: &mut Vec<OsString>) -> Result<()> {
+        let subcommand = subcommand
+            .to_str()
+            .and_then(|name| resolve_subcommand(cmd, name));
+        for (section, line) in self.sectioned_lines(cmd) {
+            if section.is_empty() || Some(&*section) == subcommand.as_deref() {
+                out.push(line.into());
             }
         }
         Ok(())
     }
 }
 
-#[derive(Debug)]
-struct ConfigInner {
-    lines: Lines<BufReader<File>>,
-    section: String,
+/// Resolve a subcommand name, alias, or unambiguous prefix of either to the full subcommand name,
+/// following the same inference rules as the command-line.
+///
+/// Unlike on the command-line, hidden subcommands (e.g. completions) are only resolved by their
+/// full name, so that they do not make prefixes of the documented subcommands ambiguous.
+fn resolve_subcommand(cmd: &Command, name: &str) -> Option<String> {
+    if let Some(subcommand) = cmd.find_subcommand(name) {
+        return Some(subcommand.get_name().to_owned());
+    }
+    let matching = cmd
+        .get_subcommands()
+        .filter(|s| !s.is_hide_set())
+        .filter(|s| {
+            s.get_name().starts_with(name) || s.get_all_aliases().any(|a| a.starts_with(name))
+        })
+        .collect::<Vec<_>>();
+    match matching[..] {
+        [unique] if !name.is_empty() => Some(unique.get_name().to_owned()),
+        _ => None,
+    }
 }
 
-impl ConfigInner {
-    fn in_section(&self, subcommand_prefix: &OsStr) -> bool {
-        self.section.is_empty()
-            || self
-                .section
-                .starts_with(subcommand_prefix.to_str().unwrap())
+/// Make a bare build -j/--jobs unambiguous, by rewriting it to --jobs=auto unless the argument
+/// after it is a job count (or "auto").
+///
+/// Clap would otherwise take whatever follows a bare -j as its value, so that e.g.
+/// "cm build -j check-llvm" failed to parse "check-llvm" as a number of jobs, rather than
+/// building check-llvm on every core. The values of other options (e.g. "--cmake-arg -j") are
+/// skipped, so they are forwarded unchanged.
+fn disambiguate_bare_jobs(cmd: &Command, sub: &OsStr, sub_args: &mut [OsString]) {
+    let resolved = sub.to_str().and_then(|name| resolve_subcommand(cmd, name));
+    let Some(build) = resolved
+        .filter(|name| name == "build")
+        .and_then(|name| cmd.find_subcommand(name))
+    else {
+        return;
+    };
+    let is_jobs_value = |arg: Option<&OsString>| {
+        arg.and_then(|a| a.to_str())
+            .is_some_and(|a| a.parse::<usize>().is_ok() || a.eq_ignore_ascii_case("auto"))
+    };
+    // Whether the option, if it is one, takes its value from the next argument.
+    let takes_next_value = |option: &str| {
+        let matches = |arg: &&Arg| match option.strip_prefix("--") {
+            Some(long) => {
+                arg.get_long() == Some(long)
+                    || arg.get_all_aliases().is_some_and(|a| a.contains(&long))
+            }
+            None => option.strip_prefix('-').is_some_and(|short| {
+                let mut chars = short.chars();
+                // Only a single short option, as a value may be attached to the last in a cluster.
+                chars.next().is_some_and(|c| {
+                    chars.next().is_none()
+                        && (arg.get_short() == Some(c)
+                            || arg.get_all_short_aliases().is_some_and(|a| a.contains(&c)))
+                })
+            }),
+        };
+        build.get_arguments().find(matches).is_some_and(|arg| {
+            !arg.is_require_equals_set() && arg.get_num_args().is_some_and(|n| n.takes_values())
+        })
+    };
+    let mut i = 0;
+    while i < sub_args.len() {
+        if sub_args[i] == "--" {
+            break;
+        }
+        if sub_args[i] == "-j" || sub_args[i] == "--jobs" {
+            if !is_jobs_value(sub_args.get(i + 1)) {
+                debug!("treating a bare {:?} as --jobs=auto", sub_args[i]);
+                sub_args[i] = "--jobs=auto".into();
+            } else {
+                i += 1;
+            }
+        } else if sub_args[i].to_str().is_some_and(takes_next_value) {
+            i += 1;
+        }
+        i += 1;
     }
 }
 
@@ -108,6 +212,10 @@ enum PreCliSub {
 
 /// Get the "cooked" args vector, incorporating the config file (if any) and moving everything
 /// under the subcommand.
+///
+/// The config file args always precede the global and subcommand args, so overriding options
+/// resolve in favor of the command-line and accumulating options see the config file entries
+/// first.
 pub fn build() -> Result<Vec<OsString>> {
     if let Ok(pre_cli) = PreCli::try_parse() {
         build_with_pre_cli(pre_cli)
@@ -124,8 +232,14 @@ fn build_with_pre_cli(pre_cli: PreCli) -> Result<Vec<OsString>> {
     if let Some(bin) = env::args_os().next() {
         args.push(bin);
     }
+    let mut cmd = Cli::command();
+    // Propagate the global options and defaults, which disambiguate_bare_jobs inspects.
+    cmd.build();
+    let config = Config::from_env()?;
+    let sub = config.expand_alias(&cmd, sub)?;
     args.push(sub.clone());
-    Config::from_env()?.slurp_into(sub.as_os_str(), &mut args)?;
+    disambiguate_bare_jobs(&cmd, &sub, &mut sub_args);
+    config.slurp_into(&cmd, sub.as_os_str(), &mut args)?;
     args.extend(pre_cli.globals.args_to_vec());
     if pre_cli.help_short {
         args.push("-h".into());
@@ -133,6 +247,8 @@ fn build_with_pre_cli(pre_cli: PreCli) -> Result<Vec<OsString>> {
     if pre_cli.help_long {
         args.push("--help".into());
     }
+    // The subcommand args must come last, as they may include a `--` after which everything is
+    // interpreted verbatim.
     args.append(&mut sub_args);
     Ok(args)
 }
@@ -4,11 +4,13 @@
 use applause::{Bool, FuzzyParser, OverridingVec, SettableBool};
 use applause_derive::ArgsToVec;
 use clap::{
-    builder::{ArgAction, ArgPredicate},
+    builder::{ArgAction, ArgPredicate, FalseyValueParser},
     ArgGroup, Args, Parser, Subcommand, ValueHint,
 };
 use std::ffi::{OsStr, OsString};
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 const GLOBAL_HEADING: Option<&str> = Some("Global Options");
 const LLVM_HEADING: Option<&str> = Some("LLVM-Specific Options");
@@ -19,7 +21,8 @@ const LLVM_HEADING: Option<&str> = Some("LLVM-Specific Options");
 /// with special support for LLVM.
 ///
 /// All subcommands share a common interface for specifying the source (-s/--source) and binary
-/// (-b/--binary) paths, as well as the config (-c/--config).
+/// (-b/--binary) paths, as well as the config (-c/--config). The cmake-style -S and -B are also
+/// accepted for the source and binary paths.
 ///
 /// Typical usage of the tool involves leaving a shell parked at the top-level of the CMake project
 /// and running subcommands (note that the subcommand can be abbreviated):
@@ -91,7 +94,10 @@ const LLVM_HEADING: Option<&str> = Some("LLVM-Specific Options");
 ///
 /// Arguments before any subcommand identifier are global, and apply to all "cm" invocations.
 /// Arguments under a specific subcommand identifier only apply for cm invocations with the
-/// appropriate subcommand specified.
+/// appropriate subcommand specified. Subcommand identifiers are resolved in the same way as on the
+/// command-line, so an alias or unambiguous prefix (e.g. "c" or "conf" for "configure") applies
+/// whichever way the subcommand is spelled on the command-line. Hidden subcommands (e.g.
+/// "completions") must be spelled in full, so they never make a prefix ambiguous.
 ///
 /// An example config:
 ///
@@ -113,6 +119,15 @@ const LLVM_HEADING: Option<&str> = Some("LLVM-Specific Options");
 ///     # do not generate a resultdb by default
 ///     --update-resultdb=false
 ///
+/// The special "aliases" section instead defines custom aliases for subcommands, one
+/// "<alias> = <subcommand>" per line. For example, with the following "cm cfg" is "cm configure":
+///
+///     aliases
+///     cfg = configure
+///     cmp = build
+///
+/// The built-in subcommand names and aliases (e.g. "c" for configure) cannot be redefined.
+///
 /// Overall, the order in which arguments are evaluated is (later wins):
 ///
 /// * Config file (e.g. ~/.config/cm.rc)
@@ -123,6 +138,20 @@ const LLVM_HEADING: Option<&str> = Some("LLVM-Specific Options");
 /// generally have an --option=false form), so that setting them in the config file does not limit
 /// the user on the command-line (i.e. you can always override your own configured defaults).
 ///
+/// Options accepting a comma-separated list (e.g. --prefix-path) are also overridden as a whole,
+/// so specifying one on the command-line replaces the whole list from the config file rather than
+/// appending to it. Any arguments which do accumulate are always collected in the order above,
+/// i.e. config file entries first followed by those from the command-line.
+///
+/// To see how the config file and command-line were combined, set the environment variable
+/// CM_DUMP_ARGS to any non-empty value and the final arguments will be printed to stderr before
+/// they are interpreted.
+///
+/// When troubleshooting cm itself, set the environment variable CM_LOG to a log level (e.g. debug or
+/// trace) to log the decisions cm makes, such as quirks detection and the results of probing for
+/// tools and compiler flags, to stderr. Attaching the output of "CM_LOG=trace cm -# <subcommand>"
+/// is helpful when reporting a bug.
+///
 #[derive(Parser)]
 #[command(version, verbatim_doc_comment, infer_subcommands = true)]
 #[command(args_override_self = true)]
@@ -138,31 +167,195 @@ pub struct Cli {
 pub struct Globals {
     /// CMake Source Directory
     ///
+    /// When the source looks like an LLVM checkout (it has no CMakeLists.txt but has an llvm
+    /// directory) and no quirks mode or project is specified, its llvm subdirectory is used instead.
+    ///
+    /// A leading "~" is expanded to the home directory, as the shell would, for paths given via
+    /// the environment or a config file. The "~user" form for the home directory of another user
+    /// is not supported, and is used as-is with a warning.
+    ///
     /// [default: .]
-    #[arg(short, long, env = "CM_SRC", value_hint = ValueHint::DirPath, global = true, help_heading = GLOBAL_HEADING)]
+    #[arg(short, long, visible_short_alias = 'S', env = "CM_SRC", value_hint = ValueHint::DirPath, global = true, help_heading = GLOBAL_HEADING)]
     pub source: Option<PathBuf>,
     /// CMake Binary Directory
     ///
+    /// A leading "~" is expanded as for -s/--source.
+    ///
     /// [default: ./build]
-    #[arg(short, long, env = "CM_BIN", value_hint = ValueHint::DirPath, global = true, help_heading = GLOBAL_HEADING)]
+    #[arg(short, long, visible_short_alias = 'B', env = "CM_BIN", value_hint = ValueHint::DirPath, global = true, help_heading = GLOBAL_HEADING)]
     pub binary: Option<PathBuf>,
+    /// Suffix the default binary directory with the config, e.g. ./build-Debug
+    ///
+    /// Only applies when -b/--binary is not specified, so that builds of each config in the same
+    /// source directory do not conflict.
+    #[arg(long, settable_bool(), global = true, help_heading = GLOBAL_HEADING)]
+    pub per_config_dir: Option<Bool>,
+    /// Subdirectory of the binary directory containing executables
+    ///
+    /// This is where lit finds llvm-lit, and what activate prepends to (and deactivate removes
+    /// from) "PATH". Override it for generators or projects with a non-standard layout, e.g.
+    /// "RelWithDebInfo/bin" for a multi-config generator.
+    ///
+    /// [default: bin]
+    #[arg(long, value_name = "PATH", global = true, help_heading = GLOBAL_HEADING)]
+    pub bin_subdir: Option<PathBuf>,
+    /// Extra rules mapping lit test IDs to paths, as PATTERN=REPLACEMENT separated by ';'
+    ///
+    /// lit maps the test IDs in the ResultDB and in --from-file (e.g. "LLVM :: CodeGen/foo.ll") to
+    /// paths under the source directory using a built-in table of the LLVM test suites. These
+    /// rules are tried first, in order, so a fork of LLVM can teach cm about its own test suites,
+    /// e.g. --test-path-map='Foo :: =../foo/test/'. Each PATTERN is a regex, separated from its
+    /// REPLACEMENT at the last '=', and the REPLACEMENT may refer to capture groups (e.g. "$1").
+    #[arg(long, env = "CM_TEST_PATH_MAP", value_name = "MAP", global = true, help_heading = GLOBAL_HEADING)]
+    pub test_path_map: Option<String>,
     /// CMake Build Config
     ///
+    /// Known configs can be abbreviated to any unambiguous prefix (e.g. -c deb for Debug), and any
+    /// other value is passed to CMake verbatim.
+    ///
     /// [default: RelWithDebInfo]
-    #[arg(short, long, env = "CM_CFG", value_parser = FuzzyParser::new(["Release", "Debug", "RelWithDebInfo", "MinSizeRel"], None), global = true, help_heading = GLOBAL_HEADING)]
+    #[arg(short, long, env = "CM_CFG", value_parser = FuzzyParser::new(["Release", "Debug", "RelWithDebInfo", "MinSizeRel"], None).allow_abbreviations(), global = true, help_heading = GLOBAL_HEADING)]
     pub config: Option<String>,
+    /// Shorthand for -c/--config=Debug, taking precedence over -c/--config when enabled
+    #[arg(short, long, settable_bool(), global = true, help_heading = GLOBAL_HEADING)]
+    pub debug: Option<Bool>,
     /// Disable quirk mode detection and specify one explicitly
     ///
     /// [default: none]
     #[arg(short, long, env = "CM_QUIRKS", global = true, help_heading = GLOBAL_HEADING)]
     pub quirks: Option<Quirks>,
+    /// Disable project detection and select a profile of project-specific defaults explicitly
+    ///
+    /// A profile implies both a quirks mode and a default source directory, for example "llvm"
+    /// implies LLVM quirks mode and a source directory of "llvm". The -q/--quirks option can be
+    /// used alongside this to override only the quirks mode.
+    #[arg(long, global = true, help_heading = GLOBAL_HEADING)]
+    pub project: Option<Project>,
     /// Perform a dry run, only printing the generated command line
     #[arg(short = '#', long, settable_bool(), global = true, help_heading = GLOBAL_HEADING)]
     pub dry_run: Option<Bool>,
+    /// Perform a dry run, writing the generated command line to FILE rather than stdout
+    ///
+    /// Each command is written on its own line, quoted for bash. Steps cm performs itself, such as
+    /// removing the CMake cache, are described rather than written as commands (e.g. "remove
+    /// <paths>"). This implies -#/--dry-run.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath, global = true, help_heading = GLOBAL_HEADING)]
+    pub dry_run_out: Option<PathBuf>,
+    /// Perform a dry run, checking the generated command line against FILE
+    ///
+    /// FILE holds the expected plan, in the same form as --dry-run-out writes it. When the plan
+    /// differs, a diff is printed to stderr and cm fails, so a CI job can pin the exact commands cm
+    /// generates and catch unintended changes (e.g. after upgrading cm). This implies -#/--dry-run.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath, global = true, help_heading = GLOBAL_HEADING)]
+    pub expect_plan: Option<PathBuf>,
+    /// Print each command to stdout immediately before running it
+    ///
+    /// Commands are printed in the same form as -#/--dry-run, but are also run, so the output
+    /// records exactly what ran. Has no effect with -#/--dry-run, which already prints every
+    /// command. See also --cm-verbose, which echoes commands to stderr instead.
+    #[arg(long, settable_bool(), global = true, help_heading = GLOBAL_HEADING)]
+    pub print_plan: Option<Bool>,
+    /// Continue running the remaining commands after one fails, still exiting with the status of
+    /// the first failure
+    #[arg(short, long, settable_bool(), global = true, help_heading = GLOBAL_HEADING)]
+    pub keep_going: Option<Bool>,
+    /// Run the shell command CMD before the first planned command
+    ///
+    /// CMD is run via "sh -c", for example to touch a stamp file or notify a dashboard. It is only
+    /// run when there is at least one other command to run, and a failure stops the plan as for
+    /// any other command.
+    #[arg(long, value_name = "CMD", global = true, help_heading = GLOBAL_HEADING)]
+    pub pre: Option<String>,
+    /// Run the shell command CMD after the last planned command
+    ///
+    /// CMD is run via "sh -c", with the CM_STATUS environment variable set to the exit status of
+    /// the first command which failed (or 1 if it had none), or to 0 if every command succeeded.
+    /// Like any other command it is skipped after a failure, unless -k/--keep-going is set.
+    #[arg(long, value_name = "CMD", global = true, help_heading = GLOBAL_HEADING)]
+    pub post: Option<String>,
+    /// Print a summary to stderr once every command has finished
+    ///
+    /// The summary lists each command with whether it succeeded (OK), failed (FAILED), or was not
+    /// run because an earlier command failed (SKIPPED), followed by the overall result. This is
+    /// most useful with -k/--keep-going, to see every step which failed. It is silenced by
+    /// --cm-quiet.
+    #[arg(long, settable_bool(), global = true, help_heading = GLOBAL_HEADING)]
+    pub summary: Option<Bool>,
+    /// Never probe for optional tools or compiler flags, falling back to conservative defaults
+    ///
+    /// Probing runs the tools and compiler, which can hang in some environments (e.g. a compiler
+    /// which checks a license server). With this, configure uses no compiler launcher (ccache),
+    /// no faster linker (lld or gold), no sphinx docs, and no optional compiler flags such as
+    /// -fcolor-diagnostics or LTO, and doctor reports everything as unavailable. Options which
+    /// are specified explicitly, such as --linker, still apply.
+    #[arg(long, settable_bool(), global = true, help_heading = GLOBAL_HEADING)]
+    pub offline: Option<Bool>,
+    /// Fail, rather than warn, when cm does not satisfy the version pinned by .cm-version
+    ///
+    /// A .cm-version file in the source directory (or any directory above it) pins the versions
+    /// of cm a project expects, as a semver requirement such as ">=0.9, <2" (where a bare version
+    /// like "0.9.0" means "^0.9.0").
+    #[arg(long, settable_bool(), global = true, help_heading = GLOBAL_HEADING)]
+    pub strict_version: Option<Bool>,
+    /// Set the environment variables in FILE for every command cm runs
+    ///
+    /// Each line of FILE is a KEY=VALUE assignment, optionally preceded by "export" and with the
+    /// VALUE optionally in single or double quotes. Blank lines and lines beginning with '#' are
+    /// ignored. The variables override the inherited environment, but not those cm sets for a
+    /// command itself (e.g. CMAKE_BUILD_PARALLEL_LEVEL for build -j/--jobs), and are shown with
+    /// -#/--dry-run. They do not apply to the probes cm runs while planning.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath, global = true, help_heading = GLOBAL_HEADING)]
+    pub env_file: Option<PathBuf>,
+    /// Print the environment variables cm sets for the commands it would run, rather than running
+    ///
+    /// Only the variables cm sets or overrides are printed, and not those inherited from its own
+    /// environment, as sorted KEY=VALUE lines. This covers both the variables cm manages itself
+    /// (e.g. LIT_OPTS and FILECHECK_OPTS for lit) and those from --env-file. A variable set to
+    /// different values for different commands is printed once for each value.
+    #[arg(long, settable_bool(), global = true, help_heading = GLOBAL_HEADING)]
+    pub dump_env: Option<Bool>,
+    /// Run every command with a minimal environment, rather than inheriting cm's own
+    ///
+    /// Only PATH and HOME are inherited, alongside the variables cm sets for a command itself and
+    /// those from --env-file, so stray variables cannot affect the build. With -#/--dry-run the
+    /// commands are shown prefixed with "env -i" and the variables they are given.
+    #[arg(long, settable_bool(), global = true, help_heading = GLOBAL_HEADING)]
+    pub isolate_env: Option<Bool>,
+    /// Increase how much cm itself prints, and may be repeated
+    ///
+    /// This only affects the output of cm, such as echoing each command (to stderr) before running
+    /// it, and not the verbosity of the tools it runs. See also --cm-quiet.
+    #[arg(long, action = ArgAction::Count, global = true, help_heading = GLOBAL_HEADING)]
+    pub cm_verbose: u8,
+    /// Decrease how much cm itself prints, and may be repeated
+    ///
+    /// Once is enough to silence warnings. Each --cm-quiet cancels out one --cm-verbose.
+    #[arg(long, action = ArgAction::Count, global = true, help_heading = GLOBAL_HEADING)]
+    pub cm_quiet: u8,
 }
 
 impl Globals {
+    /// How much cm itself prints, where 0 is the default and negative values are quieter.
+    pub fn verbosity(&self) -> i16 {
+        i16::from(self.cm_verbose) - i16::from(self.cm_quiet)
+    }
+
+    pub fn bin_subdir(&self) -> &Path {
+        self.bin_subdir.as_deref().unwrap_or(Path::new("bin"))
+    }
+
+    /// Whether commands are only printed (or written, or checked) rather than run.
+    pub fn is_dry_run(&self) -> bool {
+        matches!(self.dry_run, Some(Bool(true)))
+            || self.dry_run_out.is_some()
+            || self.expect_plan.is_some()
+            || matches!(self.dump_env, Some(Bool(true)))
+    }
+
     pub fn final_config(&self) -> &str {
+        if let Some(Bool(true)) = self.debug {
+            return "Debug";
+        }
         self.config.as_deref().unwrap_or("RelWithDebInfo")
     }
 }
@@ -182,14 +375,58 @@ impl AsRef<OsStr> for Quirks {
     }
 }
 
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum Project {
+    Generic,
+    Llvm,
+}
+
+impl AsRef<OsStr> for Project {
+    fn as_ref(&self) -> &OsStr {
+        match self {
+            Project::Generic => "generic".as_ref(),
+            Project::Llvm => "llvm".as_ref(),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 pub enum Command {
     /// CMake Configure
+    ///
+    /// The arguments to cmake are ordered deterministically: first the options and variable
+    /// definitions cm manages itself, then the trailing arguments (where any variables defined
+    /// replace cm's own definitions), and finally any --generator-arg arguments.
     #[command(visible_alias = "c")]
     Configure(Configure),
+    /// Rerun cmake on the binary directory, keeping its existing cache
+    ///
+    /// The "reconfigure" command runs cmake on the already-configured binary directory, picking up
+    /// any changes to the project's CMake code without deleting the cache or changing any
+    /// options, unlike "configure" which starts from scratch. It is an error if the binary
+    /// directory has not been configured.
+    Reconfigure(Reconfigure),
     /// CMake Build
     #[command(visible_alias = "b")]
     Build(Build),
+    /// CMake Install
+    ///
+    /// The "install" command runs "cmake --install" on the binary directory, installing into the
+    /// install prefix it was configured with ("dist" by default, see configure).
+    #[command(visible_alias = "i")]
+    Install(Install),
+    /// CTest
+    ///
+    /// The "test" command runs the tests of the binary directory with ctest, for projects which
+    /// do not use lit (see the lit command for LLVM).
+    #[command(visible_alias = "t")]
+    Test(Test),
+    /// Run ninja directly in the binary directory
+    ///
+    /// The "ninja" command runs "ninja -C <BINARY> <ARGS>...", exposing the features of ninja
+    /// which cmake --build does not, such as its tools (e.g. "cm ninja -t compdb"). It is an error
+    /// if the binary directory has not been configured with a Ninja generator.
+    Ninja(Ninja),
     /// llvm-lit
     ///
     /// The "lit" subcommand provides a powerful interface to llvm-lit (and cmake --build, to
@@ -213,15 +450,63 @@ pub enum Command {
     /// The "activate" command sets variables for the source directory ("CM_SRC"), binary directory
     /// ("CM_BIN"), configuration ("CM_CFG"), and quirks mode ("CM_QUIRKS"), which are interpreted
     /// as-if they were provided on the command-line. To simplify executing binaries in the binary
-    /// directory it also prepends the "bin" subdirectory in the binary path to the "PATH"
-    /// environment variable.
+    /// directory it also prepends the "bin" subdirectory (see --bin-subdir) in the binary path to
+    /// the "PATH" environment variable.
+    ///
+    /// The commands are for bash and zsh, e.g. to be run with `eval "$(cm activate)"`. Other shells
+    /// are not supported, so on Windows a bash such as Git Bash must be used.
     #[command(visible_alias = "a")]
     Activate(Activate),
     /// Print shell commands to deactivate global options set via activate
     ///
-    /// The "deactivate" command attempts to undo all of the effects of "activate".
+    /// The "deactivate" command attempts to undo all of the effects of "activate". Like
+    /// "activate", it prints commands for bash and zsh.
     #[command(visible_alias = "d")]
     Deactivate(Deactivate),
+    /// Diagnose the environment, checking for the tools and compiler flags cm makes use of
+    ///
+    /// The "doctor" command runs all of the checks cm would otherwise perform while planning other
+    /// subcommands, and prints a checklist describing what is available and how cm would use it.
+    Doctor(Doctor),
+    /// Print the path to the compile_commands.json in the binary directory
+    ///
+    /// The "compile-db" command prints the canonical path to the compilation database cmake
+    /// generates in the binary directory when configuring, for tools like clangd which need to
+    /// locate it. It is an error if the binary directory has not been configured.
+    CompileDb(CompileDb),
+    /// Remove the files installed from the binary directory
+    ///
+    /// The "uninstall" command reverses an install (e.g. "cm build --target=install") by removing
+    /// each file listed in the install_manifest.txt which cmake writes to the binary directory,
+    /// and reports how many were removed. Files which no longer exist are skipped, and directories
+    /// are left in place. It is an error if there is no install manifest. With -#/--dry-run the
+    /// files which would be removed are listed instead.
+    Uninstall(Uninstall),
+    /// Print the completion script for a shell
+    ///
+    /// The "completions" command prints the same completion scripts which are generated when
+    /// building cm, for installations which do not include them, for example:
+    ///
+    ///     $ cm completions bash > ~/.local/share/bash-completion/completions/cm
+    #[command(hide = true)]
+    Completions(Completions),
+    /// Print the manpage for cm or one of its subcommands
+    ///
+    /// The "man" command prints the same manpages (as roff) which are generated when building cm,
+    /// for installations which do not include them, for example:
+    ///
+    ///     $ cm man configure | man -l -
+    #[command(hide = true)]
+    Man(Man),
+    /// Print the active configuration for a shell prompt
+    ///
+    /// The "prompt" command prints a compact summary of the quirks mode and config set via
+    /// activate, such as "[llvm:Debug]", and nothing at all when activate has not been run, for
+    /// example:
+    ///
+    ///     $ PS1='$(cm prompt)'"$PS1"
+    #[command(hide = true)]
+    Prompt(Prompt),
 }
 
 #[derive(Args)]
@@ -232,22 +517,163 @@ pub struct Configure {
     /// CMake Generator
     #[arg(short, long, default_value = "Ninja")]
     pub generator: String,
+    /// Forward a generator-specific argument to cmake, after all other arguments
+    ///
+    /// May be specified multiple times, e.g. to select a platform and toolset:
+    ///
+    ///     $ cm configure -G "Visual Studio 17 2022" --generator-arg=-A --generator-arg=x64
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    pub generator_arg: Vec<OsString>,
+    /// Select the Visual Studio installation to use, via CMAKE_GENERATOR_INSTANCE
+    ///
+    /// Only applies to the Visual Studio generators, and it is an error to specify it with any
+    /// other generator. For example:
+    ///
+    ///     $ cm configure --generator="Visual Studio 17 2022" --vs-instance="C:/BuildTools/2022"
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::DirPath)]
+    pub vs_instance: Option<PathBuf>,
+    /// Set the build tool cmake uses for the generator, via CMAKE_MAKE_PROGRAM
+    ///
+    /// For a build tool which is not on "PATH", for example:
+    ///
+    ///     $ cm configure --make-program=/opt/ninja/bin/ninja
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pub make_program: Option<PathBuf>,
     /// Set BUILD_SHARED_LIBS
     #[arg(long, settable_bool(), default_value_t = true)]
     pub shared_libs: bool,
     /// Enable ASan and UBSan
     #[arg(long, settable_bool())]
     pub san: bool,
+    /// Append FLAGS to the C compiler flags, via CMAKE_C_FLAGS
+    ///
+    /// May be specified multiple times. The flags follow those cm chooses itself and any in the
+    /// CFLAGS environment variable, so this is a way to set per-language flags from the config
+    /// file, for example:
+    ///
+    ///     configure
+    ///     --cflags=-Wno-unused-function
+    #[arg(
+        long,
+        visible_alias = "cflag",
+        value_name = "FLAGS",
+        allow_hyphen_values = true
+    )]
+    pub cflags: Vec<String>,
+    /// Append FLAGS to the C++ compiler flags, via CMAKE_CXX_FLAGS
+    ///
+    /// As for --cflags, but following any flags in the CXXFLAGS environment variable.
+    #[arg(
+        long,
+        visible_alias = "cxxflag",
+        value_name = "FLAGS",
+        allow_hyphen_values = true
+    )]
+    pub cxxflags: Vec<String>,
+    /// Use cmake --fresh to discard the existing cache, rather than deleting it directly
+    ///
+    /// Requires cmake 3.24 or later.
+    #[arg(long, settable_bool())]
+    pub fresh: bool,
+    /// Set CMAKE_INSTALL_PREFIX to PATH, rather than the default of "dist"
+    ///
+    /// For example, to package into a versioned prefix:
+    ///
+    ///     $ cm configure --install-prefix=/opt/llvm-19
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::DirPath, conflicts_with = "no_install_prefix")]
+    pub install_prefix: Option<PathBuf>,
+    /// Do not set CMAKE_INSTALL_PREFIX, which otherwise defaults to "dist"
+    ///
+    /// This leaves the install prefix to cmake, or to a toolchain file or preset which sets it.
+    #[arg(long, settable_bool())]
+    pub no_install_prefix: bool,
+    /// Set the log level of cmake itself, via cmake --log-level
+    ///
+    /// Requires cmake 3.16 or later.
+    #[arg(long, value_name = "LEVEL", ignore_case = true)]
+    pub cmake_log: Option<CMakeLogLevel>,
+    /// Prefix cmake's log messages with their context, via cmake --log-context
+    ///
+    /// Requires cmake 3.17 or later.
+    #[arg(long, settable_bool())]
+    pub log_context: bool,
+    /// Suppress cmake's developer warnings, via cmake -Wno-dev
+    ///
+    /// These warnings are aimed at the authors of the project's CMake code, so are mostly noise
+    /// when just configuring it.
+    #[arg(long, settable_bool(), conflicts_with = "werror_dev")]
+    pub no_dev_warnings: bool,
+    /// Make cmake's developer warnings errors, via cmake -Werror=dev
+    #[arg(long, settable_bool())]
+    pub werror_dev: bool,
     /// Set the preferred linker.
     ///
-    /// This is honored on a best-effort basis, and is only currently implemented for
-    /// LLVM quirks mode, where the default is to try to use lld or gold if they are available.
-    /// This default is intended to work around extremely slow or impossible link steps
+    /// In LLVM quirks mode this sets LLVM_USE_LINKER, and otherwise it adds -fuse-ld=<LINKER> to
+    /// the linker flags. A warning is printed if the linker cannot be found, but it is used anyway.
+    ///
+    /// When unset, the default for LLVM quirks mode is to try to use lld or gold if they are
+    /// available. This default is intended to work around extremely slow or impossible link steps
     /// for debug builds of LLVM when using the system linker in many environments.
     ///
     /// Specify "default" to explicitly disable automatic linker selection and use the system default.
     #[arg(long, value_parser = FuzzyParser::new(["lld", "gold", "mold", "bfd", "default"], None))]
     pub linker: Option<String>,
+    /// Set the compiler launchers, e.g. --launcher=ccache,distcc
+    ///
+    /// Accepts comma-separated arguments, which are run in order, and sets both
+    /// CMAKE_C_COMPILER_LAUNCHER and CMAKE_CXX_COMPILER_LAUNCHER to the list. In LLVM quirks mode a
+    /// lone "ccache" sets LLVM_CCACHE_BUILD instead. A warning is printed if a launcher cannot be
+    /// found, but it is used anyway. If CCACHE_DIR or SCCACHE_DIR names a missing directory for a
+    /// ccache or sccache launcher, it is created before running cmake.
+    ///
+    /// When unset, ccache is used if it is available. Specify an empty list (--launcher=) to use no
+    /// launcher at all.
+    #[arg(long, overriding_vec())]
+    pub launcher: Option<Vec<String>>,
+    /// Disable compiler caching entirely, never using ccache or sccache as a launcher
+    ///
+    /// ccache is then not detected, no LLVM_CCACHE_BUILD is set in LLVM quirks mode, and any
+    /// ccache or sccache in --launcher is dropped (other launchers, such as distcc, are kept). Set
+    /// CM_NO_CACHE to any value other than an empty string, "0", "false", "no", or "off" to
+    /// disable caching for every configure, e.g. while chasing a miscompile.
+    #[arg(long, settable_bool(), env = "CM_NO_CACHE", value_parser = FalseyValueParser::new())]
+    pub no_cache: bool,
+    /// Print a report to stderr explaining each automatic decision made while configuring
+    ///
+    /// For example, this describes which quirks mode was chosen, and whether tools like ccache or
+    /// linkers like lld were detected and used.
+    #[arg(short, long, settable_bool())]
+    pub explain: bool,
+    /// Print the cache variables cmake would be given, rather than configuring
+    ///
+    /// Each variable defined with -D is printed as <VAR>=<VALUE> on its own line, in sorted order,
+    /// including any defined by the trailing arguments. This is a more focused view of the cache
+    /// configuration than -#/--dry-run, which prints the whole cmake command line.
+    #[arg(long, settable_bool(), conflicts_with = "diff_config")]
+    pub show_cache_vars: bool,
+    /// Print how the cache variables cm would set differ from the existing cache, rather than
+    /// configuring
+    ///
+    /// Compares the variables which would be defined with -D (as for --show-cache-vars) against
+    /// the CMakeCache.txt in the binary directory, printing a line for each variable which would
+    /// be "added" or "changed", and for each variable previously specified on the command line
+    /// which would be "removed". Without an existing cache every variable is added. This helps to
+    /// decide whether an old binary directory needs to be reconfigured.
+    #[arg(long, settable_bool())]
+    pub diff_config: bool,
+    /// Enable link-time optimization, defaulting to thin LTO
+    ///
+    /// In LLVM quirks mode this sets LLVM_ENABLE_LTO, and otherwise adds -flto=thin or -flto to
+    /// the compiler flags (if the compiler supports it). Note that LTO dramatically increases
+    /// link times and memory use, especially for full LTO, so is best reserved for release builds.
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "thin", help_heading = LLVM_HEADING)]
+    pub lto: Option<Lto>,
+    /// Set LLVM_ENABLE_ASSERTIONS
+    ///
+    /// Assertions are enabled by default, as they catch bugs during development, but can be
+    /// disabled with --assertions=false (e.g. for performance testing a release build).
+    #[arg(long, settable_bool(), default_value_t = true, help_heading = LLVM_HEADING)]
+    pub assertions: bool,
     /// Enable expensive checks
     #[arg(long, settable_bool(), help_heading = LLVM_HEADING)]
     pub expensive_checks: bool,
@@ -281,12 +707,106 @@ pub struct Configure {
     /// Disable implicit "Native" target in -t/--targets-to-build
     #[arg(short = 'T', long, settable_bool(), help_heading = LLVM_HEADING)]
     pub disable_implicit_native: bool,
+    /// Use TARGET in place of the implicit "Native" target in -t/--targets-to-build
+    ///
+    /// "Native" is the target of the host machine, so when cross-compiling this treats another
+    /// target as native instead. For example, on an x86_64 host machine the following command-line
+    /// will enable AArch64 and AMDGPU (but not X86):
+    ///
+    ///     $ cm configure --native-target=AArch64 -t AMDGPU
+    ///
+    /// Like the implicit "Native" target, this only applies when -t/--targets-to-build is given.
+    #[arg(long, value_name = "TARGET", conflicts_with = "disable_implicit_native", value_parser = FuzzyParser::new(include!("../values/llvm_all_targets.in"), None), help_heading = LLVM_HEADING)]
+    pub native_target: Option<String>,
     /// Trailing arguments to forward to cmake
+    ///
+    /// Any variable defined here (via -D<VAR>=<VALUE> or -D <VAR>=<VALUE>) replaces the definition
+    /// cm would otherwise generate for it.
+    ///
+    /// An argument of the form @FILE is replaced by the arguments in the response file FILE, one
+    /// per line. Response files may refer to other response files, relative to their own directory.
     pub args: Vec<OsString>,
 }
 
 #[derive(Args)]
 pub struct Build {
+    /// Argument to forward to cmake --build, rather than to the build tool
+    ///
+    /// May be repeated, and each value is inserted before the -- separator in the generated
+    /// command-line. Values beginning with a '-' must be attached using '=', for example:
+    ///
+    ///     $ cm build --cmake-arg=--verbose --cmake-arg=--parallel=8
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    pub cmake_arg: Vec<OsString>,
+    /// Build the named target(s) via cmake --build --target, independent of the generator
+    ///
+    /// Accepts comma-separated arguments (e.g. --target=install,foo). Any target name is
+    /// accepted, but the generator-agnostic pseudo-targets "all", "install", and "clean" are
+    /// recognized case-insensitively. The "clean" target cannot be combined with other targets.
+    ///
+    /// The "install/strip" target, which installs stripped binaries, is also recognized, but is
+    /// only provided by the Ninja and Makefile generators.
+    #[arg(long, overriding_vec(), value_parser = FuzzyParser::new(["all", "install", "install/strip", "clean"], None))]
+    pub target: Option<Vec<String>>,
+    /// Build the sphinx HTML documentation for each enabled LLVM project
+    ///
+    /// Adds a docs-<project>-html target (e.g. docs-clang-html) for LLVM and each project in the
+    /// cached LLVM_ENABLE_PROJECTS, alongside any --target. Requires LLVM quirks mode, and a
+    /// binary directory configured with LLVM_ENABLE_SPHINX (see configure).
+    #[arg(long, settable_bool())]
+    pub docs: bool,
+    /// Only build the targets affected by the changes in the source directory since the git
+    /// revision REV
+    ///
+    /// Each changed source file is mapped to the target which compiles it, via the
+    /// compile_commands.json in the binary directory. This is best-effort: when the changes
+    /// cannot all be mapped (e.g. a header or CMakeLists.txt changed), or the source is not a git
+    /// repository, or the binary directory has not been configured, everything is built instead,
+    /// with a warning. Changes to other files, such as tests and docs, are ignored.
+    #[arg(long, value_name = "REV", conflicts_with_all = ["target", "docs"])]
+    pub since: Option<String>,
+    /// List the targets available to build (e.g. with --target), rather than building
+    ///
+    /// Runs "ninja -t targets" in the binary directory for the Ninja generators, and builds the
+    /// "help" target otherwise. It is an error if the binary directory has not been configured.
+    #[arg(long, settable_bool(), conflicts_with_all = ["target", "docs", "since", "trace", "cache_stats"])]
+    pub list: bool,
+    /// Run at most N jobs in parallel, via cmake --build -j N
+    ///
+    /// The CMAKE_BUILD_PARALLEL_LEVEL environment variable is also set to N, so that any nested
+    /// cmake --build (e.g. of an ExternalProject) runs at most N jobs as well. Without this, any
+    /// CMAKE_BUILD_PARALLEL_LEVEL already set in the environment is passed through unchanged, and
+    /// otherwise the build tool chooses its own default.
+    ///
+    /// With -j/--jobs=auto, N is the number of cores available to cm, as it is for a bare -j which
+    /// is not followed by a number (e.g. "cm build -j check-llvm"). This applies to every job,
+    /// including links, which for LLVM can exhaust memory well before the cores are busy. To cap
+    /// only the links when building with Ninja, configure with e.g.
+    /// "cm configure -- -DLLVM_PARALLEL_LINK_JOBS=2", and build with -j/--jobs=auto as usual.
+    #[arg(short, long, value_name = "N", num_args = 0..=1, default_missing_value = "auto")]
+    pub jobs: Option<Jobs>,
+    /// Fail if the binary directory was configured for a different config than requested
+    ///
+    /// A single-config generator (such as Ninja) builds whichever CMAKE_BUILD_TYPE the binary
+    /// directory was configured with, regardless of -c/--config. This checks the CMakeCache.txt
+    /// first, to catch silently building e.g. Release when Debug was requested. Binary directories
+    /// which are not yet configured, or which use a multi-config generator, are not checked.
+    #[arg(long, settable_bool())]
+    pub strict_config: bool,
+    /// Write a Chrome tracing profile of the build steps to FILE, for analyzing build performance
+    ///
+    /// Once the build succeeds, the timings ninja records in the .ninja_log of the binary directory
+    /// are converted to a trace of the most recent build, which can be opened in e.g.
+    /// chrome://tracing or https://ui.perfetto.dev. Requires the binary directory to be configured
+    /// with a Ninja generator.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub trace: Option<PathBuf>,
+    /// Print the statistics of the compiler cache once the build succeeds
+    ///
+    /// Runs "ccache -s" or "sccache --show-stats", for whichever compiler launcher the binary
+    /// directory was configured with (see configure --launcher). It is an error if neither was.
+    #[arg(long, settable_bool())]
+    pub cache_stats: bool,
     /// Trailing arguments to forward to build tool
     pub args: Vec<OsString>,
 }
@@ -295,6 +815,9 @@ pub struct Build {
 #[command(group = ArgGroup::new("select").multiple(false))]
 pub struct Lit {
     /// Print tests that would be run
+    ///
+    /// With -g/--group or --parallel-groups, which leave selecting the tests to the build, this
+    /// prints the commands which would run them instead.
     #[arg(short, long, settable_bool())]
     pub print_only: bool,
     /// Print a command-line which exports LIT_XFAIL to the tests that would be run
@@ -302,8 +825,8 @@ pub struct Lit {
     pub xfail_export: bool,
     /// Update the ResultDB file.
     ///
-    /// Defaults to true unless -1/--first or a list of tests (via positional arguments) are
-    /// specified.
+    /// Defaults to true unless -1/--first, --since, --from-file, --lit, or a list of tests (via
+    /// positional arguments) are specified.
     ///
     /// Accepts explicit argument via -u/--update-resultdb=true or -u/--update-resultdb=false
     /// and has a shorthand -u/--update-resultdb for the former.
@@ -312,8 +835,19 @@ pub struct Lit {
           default_missing_value = "true",
           default_value_if("first", ArgPredicate::IsPresent, Some("false")),
           default_value_if("tests", ArgPredicate::IsPresent, Some("false")),
+          default_value_if("since", ArgPredicate::IsPresent, Some("false")),
+          default_value_if("from_file", ArgPredicate::IsPresent, Some("false")),
+          default_value_if("lit", ArgPredicate::IsPresent, Some("false")),
     )]
     pub update_resultdb: bool,
+    /// Merge the results into the ResultDB rather than replacing it.
+    ///
+    /// Without this, updating the ResultDB while running a subset of the tests forgets the results
+    /// of all other tests. With it, llvm-lit records the results separately and cm merges them into
+    /// the ResultDB once the tests have finished, even if some failed. Has no effect unless the
+    /// ResultDB is being updated, and --batch and --parallel-groups runs always merge.
+    #[arg(short, long, settable_bool())]
+    pub merge_resultdb: bool,
     /// Run the named LLVM "check-*" test group, and (by default) update the ResultDB.
     ///
     /// For known groups ("possible values") the name can be shortened by omitting the "check-"
@@ -322,14 +856,104 @@ pub struct Lit {
     /// the full name including the "check-" prefix must be specified.
     #[arg(short, long, group = "select", value_parser = FuzzyParser::new(["all", "llvm", "clang", "lld"], Some("check-")))]
     pub group: Option<String>,
+    /// Run several "check-*" test groups concurrently, each via its own build tool invocation
+    ///
+    /// Accepts comma-separated group names, abbreviated as for -g/--group. Each group is run as
+    /// -g/--group would run it, but all at once (see --max-parallel), for faster feedback than one
+    /// large group. When updating the ResultDB each group records its results separately, as for
+    /// --batch, and they are merged into the ResultDB once every group has finished.
+    ///
+    /// The build tool invocations share the binary directory without coordinating, so any
+    /// out-of-date targets the groups depend on in common (e.g. FileCheck) may be rebuilt by
+    /// several of them at once, and with Ninja they also race to update the .ninja_log and
+    /// .ninja_deps files. Build the test dependencies first (e.g. "cm build"), so the groups only
+    /// run tests. A warning is printed when running Ninja builds concurrently, and
+    /// --max-parallel=1 avoids the hazard entirely.
+    #[arg(long, value_name = "GROUPS", group = "select", overriding_vec(), value_parser = FuzzyParser::new(["all", "llvm", "clang", "lld"], Some("check-")))]
+    pub parallel_groups: Option<Vec<String>>,
+    /// Run at most N of the --parallel-groups at once
+    ///
+    /// Defaults to running every group at once.
+    #[arg(long, value_name = "N", requires = "parallel_groups")]
+    pub max_parallel: Option<NonZeroUsize>,
     /// Only consider at most the first failing test in the ResultDB.
     #[arg(short = '1', long, group = "select")]
     pub first: bool,
+    /// Skip the failing tests recalled from the ResultDB which no longer exist, with a warning
+    ///
+    /// A test which has since been renamed or deleted otherwise stays in the ResultDB, and makes
+    /// llvm-lit fail before running any of the other tests. Skipped tests are dropped before
+    /// -1/--first picks the first test.
+    #[arg(long, settable_bool())]
+    pub skip_missing: bool,
+    /// Run the tests changed in the source directory since the git revision REV
+    ///
+    /// Only files beneath a "test" directory (and not beneath an "Inputs" directory) which still
+    /// exist are considered tests, and all other changes are ignored. It is an error for no tests
+    /// to have changed.
+    #[arg(long, value_name = "REV", group = "select")]
+    pub since: Option<String>,
+    /// Run exactly the tests listed in FILE, one per line
+    ///
+    /// Each line is either a test path or a lit test ID (e.g. "LLVM :: CodeGen/foo.ll", as
+    /// recorded in the ResultDB), which is mapped to a path in the same way as when running the
+    /// failing tests from the ResultDB. Blank lines and lines beginning with '#' are ignored. It is
+    /// an error for the file to list no tests.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath, group = "select")]
+    pub from_file: Option<PathBuf>,
+    /// Run the whole test suite in the binary directory (or --test-dir), ignoring the ResultDB
+    ///
+    /// Rather than only the failing tests recalled from the ResultDB, llvm-lit runs every test
+    /// directly, and (by default) the ResultDB is rebuilt from scratch with the results. Unlike
+    /// -g/--group=check-all this does not go through the build tool, so nothing is rebuilt first.
+    #[arg(long, group = "select")]
+    pub all: bool,
+    /// Run the test suite in DIR, rather than the one in the binary directory
+    ///
+    /// This is for suites outside of the usual LLVM layout, such as those of a standalone runtime
+    /// or of an installed toolchain (see --lit). Any TESTS are relative to DIR, and otherwise (or
+    /// with --all) the whole suite is run, as the ResultDB only describes the binary directory.
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath, conflicts_with_all = ["group", "parallel_groups", "first", "since", "from_file"])]
+    pub test_dir: Option<PathBuf>,
+    /// Run PROGRAM as llvm-lit, rather than the llvm-lit in the binary directory
+    ///
+    /// PROGRAM may be a path, or a bare name to look up in "PATH", e.g. to test an installed
+    /// toolchain with "cm lit --lit=llvm-lit --test-dir=<DIR>". As the binary directory is then
+    /// not assumed to be involved, the tests must be selected explicitly (not recalled from the
+    /// ResultDB), and the ResultDB is not updated by default.
+    #[arg(long, value_name = "PROGRAM", value_hint = ValueHint::CommandName, conflicts_with_all = ["group", "parallel_groups"])]
+    pub lit: Option<OsString>,
+    /// Skip the tests whose paths match the regex PATTERN
+    ///
+    /// May be repeated, and applies to however the tests are selected, before -1/--first picks
+    /// the first test. A plain substring such as "CodeGen/foo.ll" is also a valid pattern. This
+    /// is useful to set aside a known-broken test without forgetting its status in the ResultDB.
+    /// It is an error if the tests are selected explicitly and every one of them is skipped.
+    #[arg(long, value_name = "PATTERN", conflicts_with_all = ["group", "parallel_groups"])]
+    pub exclude: Vec<String>,
+    /// Run the tests in sequential batches of at most N tests, printing progress between them
+    ///
+    /// When updating the ResultDB each batch records its results as it completes, so interrupting
+    /// the run still records the results of the completed batches. Combine with -k/--keep-going
+    /// to continue with the remaining batches when tests fail.
+    #[arg(long, value_name = "N")]
+    pub batch: Option<NonZeroUsize>,
+    /// Ask llvm-lit to order the tests itself, via its --order option
+    ///
+    /// This is an alternative to the ResultDB: when no tests are selected explicitly the whole
+    /// test suite in the binary directory is run (rather than only the failing tests in the
+    /// ResultDB) and llvm-lit decides the order, e.g. running previously failing tests first with
+    /// "failing-first". The ResultDB is still updated as usual.
+    #[arg(long, conflicts_with = "first")]
+    pub order: Option<LitOrder>,
     /// Be as verbose as possible, asking FileCheck to dump its input and asking llvm-lit to
     /// forward it to stdout
     #[arg(short, long, settable_bool())]
     pub verbose: bool,
     /// Lit test paths to run
+    ///
+    /// Paths may be glob patterns (e.g. 'test/CodeGen/AMDGPU/*.ll', quoted to keep the shell from
+    /// expanding them), which are expanded by cm. It is an error for a pattern to match nothing.
     #[arg(group = "select")]
     pub tests: Vec<OsString>,
     /// Trailing arguments to forward to llvm-lit
@@ -338,12 +962,165 @@ pub struct Lit {
     /// arguments, which is inconsistent with other subcommands like configure and build. This is a
     /// compromise to make explicit passing of tests more ergonomic such that the default case
     /// requires no additional flags or separators.
+    ///
+    /// With -g/--group these are forwarded via the LIT_OPTS environment variable instead, as the
+    /// build tool does not forward arguments to llvm-lit.
     #[arg(last = true)]
     pub args: Vec<OsString>,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum LitOrder {
+    Lexical,
+    Random,
+    FailingFirst,
+    Smart,
+}
+
+impl AsRef<OsStr> for LitOrder {
+    fn as_ref(&self) -> &OsStr {
+        match self {
+            LitOrder::Lexical => "lexical".as_ref(),
+            LitOrder::Random => "random".as_ref(),
+            LitOrder::FailingFirst => "failing-first".as_ref(),
+            LitOrder::Smart => "smart".as_ref(),
+        }
+    }
+}
+
+/// The -j/--jobs for build and test: either a number of jobs, or "auto" to detect the available cores.
+#[derive(Clone, Copy)]
+pub enum Jobs {
+    Auto,
+    Count(NonZeroUsize),
+}
+
+impl FromStr for Jobs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Jobs::Auto);
+        }
+        s.parse()
+            .map(Jobs::Count)
+            .map_err(|_| "expected a positive number or \"auto\"".to_owned())
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum Lto {
+    Thin,
+    Full,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum CMakeLogLevel {
+    #[value(name = "ERROR")]
+    Error,
+    #[value(name = "WARNING")]
+    Warning,
+    #[value(name = "NOTICE")]
+    Notice,
+    #[value(name = "STATUS")]
+    Status,
+    #[value(name = "VERBOSE")]
+    Verbose,
+    #[value(name = "DEBUG")]
+    Debug,
+    #[value(name = "TRACE")]
+    Trace,
+}
+
+impl AsRef<OsStr> for CMakeLogLevel {
+    fn as_ref(&self) -> &OsStr {
+        match self {
+            CMakeLogLevel::Error => "ERROR".as_ref(),
+            CMakeLogLevel::Warning => "WARNING".as_ref(),
+            CMakeLogLevel::Notice => "NOTICE".as_ref(),
+            CMakeLogLevel::Status => "STATUS".as_ref(),
+            CMakeLogLevel::Verbose => "VERBOSE".as_ref(),
+            CMakeLogLevel::Debug => "DEBUG".as_ref(),
+            CMakeLogLevel::Trace => "TRACE".as_ref(),
+        }
+    }
+}
+
 #[derive(Args)]
 pub struct Activate {}
 
 #[derive(Args)]
 pub struct Deactivate {}
+
+#[derive(Args)]
+pub struct Doctor {}
+
+#[derive(Args)]
+pub struct CompileDb {}
+
+#[derive(Args)]
+pub struct Reconfigure {}
+
+#[derive(Args)]
+pub struct Install {
+    /// Only install the files of the install component NAME
+    #[arg(long, value_name = "NAME")]
+    pub component: Option<String>,
+    /// Strip the installed binaries
+    #[arg(long, settable_bool())]
+    pub strip: bool,
+    /// Trailing arguments to forward to cmake --install
+    pub args: Vec<OsString>,
+}
+
+#[derive(Args)]
+pub struct Test {
+    /// Only run the tests whose names match REGEX, via ctest -R
+    #[arg(short = 'R', long, value_name = "REGEX")]
+    pub tests_regex: Option<String>,
+    /// Only run the tests with a label matching REGEX, via ctest -L
+    #[arg(short = 'L', long, value_name = "REGEX")]
+    pub label: Option<String>,
+    /// Run at most N tests in parallel, via ctest -j
+    ///
+    /// With -j/--jobs=auto, N is the number of cores available to cm.
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<Jobs>,
+    /// Only run the tests which failed in the previous run, via ctest --rerun-failed
+    #[arg(long, settable_bool())]
+    pub rerun_failed: bool,
+    /// Trailing arguments to forward to ctest
+    pub args: Vec<OsString>,
+}
+
+#[derive(Args)]
+pub struct Ninja {
+    /// Arguments to forward to ninja
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<OsString>,
+}
+
+#[derive(Args)]
+pub struct Uninstall {}
+
+#[derive(Args)]
+pub struct Man {
+    /// The subcommand to print the manpage for, rather than cm itself
+    pub subcommand: Option<String>,
+}
+
+#[derive(Args)]
+pub struct Prompt {}
+
+#[derive(Args)]
+pub struct Completions {
+    /// The shell to print the completion script for
+    pub shell: clap_complete::Shell,
+}
+
+/// Render the manpage for `cmd` (either cm or one of its subcommands) as roff.
+///
+/// Shared between build.rs and the "man" subcommand so the two always produce the same pages.
+pub fn render_man(cmd: clap::Command, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+    clap_mangen::Man::new(cmd).render(out)
+}
@@ -2,23 +2,66 @@
 // SPDX-License-Identifier: MIT
 
 use crate::args;
-use crate::cli::{Activate, Build, Cli, Command, Configure, Deactivate, Lit, Quirks};
-use anyhow::{Context, Error, Result};
+use crate::cli::{
+    render_man, Activate, Build, Cli, Command, CompileDb, Configure, Deactivate, Doctor, Install,
+    Jobs, Lit, Lto, Ninja, Project, Quirks, Reconfigure, Test, Uninstall,
+};
+use anyhow::{bail, Context, Error, Result};
 use applause::Bool;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use log::{debug, trace};
 use regex::Regex;
+use semver::{Version, VersionReq};
 use serde::Deserialize;
-use shell_quote::{Bash, Quotable, QuoteInto};
+use shell_quote::{Bash, Quotable, QuoteInto, Sh};
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::error;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::fs::File;
-use std::io::BufReader;
+use std::fs::{self, File};
 use std::io::ErrorKind::NotFound;
-use std::path::{absolute, Path, PathBuf};
-use std::process::{self, Stdio};
-use std::sync::LazyLock;
+use std::io::{self, BufReader, Write};
+use std::num::NonZeroUsize;
+use std::path::{absolute, Component, Path, PathBuf};
+use std::process::{self, ExitStatus, Stdio};
+use std::slice;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+/// How much cm itself prints, as set by --cm-verbose and --cm-quiet.
+static VERBOSITY: OnceLock<i16> = OnceLock::new();
+
+fn verbosity() -> i16 {
+    VERBOSITY.get().copied().unwrap_or_default()
+}
+
+static OFFLINE: OnceLock<bool> = OnceLock::new();
+
+/// Whether --offline disabled probing for optional tools and compiler flags.
+fn offline() -> bool {
+    OFFLINE.get().copied().unwrap_or_default()
+}
+
+static ISOLATE_ENV: OnceLock<bool> = OnceLock::new();
+
+/// Whether --isolate-env stopped the planned commands inheriting our environment.
+fn isolate_env() -> bool {
+    ISOLATE_ENV.get().copied().unwrap_or_default()
+}
+
+/// The `PATH` for the commands cm runs, when it differs from our own (see `adjusted_path`).
+static ADJUSTED_PATH: OnceLock<Option<OsString>> = OnceLock::new();
+
+/// Print a warning to stderr, unless silenced by --cm-quiet.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if verbosity() >= 0 {
+            eprintln!("Warning: {}", format_args!($($arg)*));
+        }
+    };
+}
 
 /// Newtype to capture exit codes from failing commands, as we want to handle these differently
 /// than generic failures.
@@ -34,17 +77,72 @@ impl fmt::Display for CommandFailedError {
     }
 }
 
-#[derive(Deserialize)]
 struct ResultDB {
     tests: Vec<ResultDBTest>,
 }
 
+/// The ResultDB as written by lit, where only the fields we need are required and any others
+/// (which vary between lit versions) are ignored.
+#[derive(Deserialize)]
+struct RawResultDB {
+    #[serde(default)]
+    tests: Vec<serde_json::Value>,
+}
+
 impl ResultDB {
     fn parse(paths: Paths) -> Result<ResultDB> {
         let path = lit_json_path(paths)?;
-        let file = File::open(&path).with_context(|| format!("could not open {path:?}"))?;
+        let mut rdb = Self::parse_file(&path)?;
+        // Each lit --batch is merged into the ResultDB once it finishes, but overlay any batch
+        // newer than the ResultDB itself, oldest first, in case cm was interrupted before it could
+        // merge a batch which completed.
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        let mut batches = vec![];
+        for entry in fs::read_dir(path.parent().unwrap_or(&path))? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !name.starts_with("lit.batch-") || !name.ends_with(".json") {
+                continue;
+            }
+            let batch_modified = entry.metadata()?.modified()?;
+            if modified.is_none_or(|m| batch_modified > m) {
+                batches.push((batch_modified, entry.path()));
+            }
+        }
+        batches.sort();
+        for (_, batch) in batches {
+            rdb.overlay(Self::parse_file(&batch)?);
+        }
+        Ok(rdb)
+    }
+
+    fn parse_file(path: &Path) -> Result<ResultDB> {
+        let file = File::open(path).with_context(|| format!("could not open {path:?}"))?;
         let reader = BufReader::new(file);
-        serde_json::from_reader(reader).with_context(|| format!("could not parse {path:?}"))
+        let raw: RawResultDB =
+            serde_json::from_reader(reader).with_context(|| format!("could not parse {path:?}"))?;
+        // Parse each test individually so an error can identify the offending entry.
+        let tests = raw
+            .tests
+            .into_iter()
+            .enumerate()
+            .map(|(i, test)| {
+                serde_json::from_value(test)
+                    .with_context(|| format!("could not parse test at index {i} in {path:?}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(ResultDB { tests })
+    }
+
+    /// Replace or add the results for each test in `other`.
+    fn overlay(&mut self, other: ResultDB) {
+        for test in other.tests {
+            match self.tests.iter_mut().find(|t| t.test_id == test.test_id) {
+                Some(t) => *t = test,
+                None => self.tests.push(test),
+            }
+        }
     }
 }
 
@@ -56,7 +154,9 @@ struct ResultDBTest {
 }
 
 impl ResultDBTest {
-    fn test_path(&self, paths: Paths) -> PathBuf {
+    /// Map the test ID to a path, trying the `extra` rules from --test-path-map before the
+    /// built-in table.
+    fn test_path(&self, paths: Paths, extra: &[(Regex, String)]) -> PathBuf {
         fn case(find: &'static str, replace: &'static str) -> (Regex, &'static str) {
             // An error compiling the regex is a dev-time failure
             (Regex::new(find).expect("invalid resultdb regex"), replace)
@@ -89,10 +189,11 @@ impl ResultDBTest {
                 case(r"Polly - isl unit tests :: .*", "../polly/test/UnitIsl"),
             ]
         });
-        for (find, replace) in REGEXES.iter() {
+        let extra = extra.iter().map(|(find, replace)| (find, replace.as_str()));
+        for (find, replace) in extra.chain(REGEXES.iter().map(|(find, replace)| (find, *replace))) {
             if find.is_match(&self.test_id) {
                 let mut path = paths.source.to_owned();
-                path.push(find.replace(&self.test_id, &**replace).into_owned());
+                path.push(find.replace(&self.test_id, replace).into_owned());
                 return path;
             }
         }
@@ -113,14 +214,55 @@ fn plan_configure(
     cli: &Cli,
     quirks: Quirks,
     paths: Paths,
-) -> Result<Vec<process::Command>> {
+) -> Result<Vec<PlannedCommand>> {
+    macro_rules! explain {
+        ($($arg:tt)*) => {
+            if configure.explain {
+                eprintln!("Explain: {}", format_args!($($arg)*));
+            }
+        };
+    }
     let mut cmd = adjust_path(process::Command::new("cmake"));
     let mut flags = Vec::<String>::new();
+    let mut link_flags = Vec::<String>::new();
+    match (cli.globals.quirks, cli.globals.project, quirks) {
+        (Some(_), _, _) => explain!(
+            "using {} quirks mode, as specified explicitly",
+            quirks.as_ref().to_string_lossy()
+        ),
+        (None, Some(project), _) => explain!(
+            "using {} quirks mode, as implied by the {} project profile",
+            quirks.as_ref().to_string_lossy(),
+            project.as_ref().to_string_lossy()
+        ),
+        (None, None, Quirks::Llvm) => explain!(
+            "using llvm quirks mode, as the source has no CMakeLists.txt but has an llvm directory"
+        ),
+        (None, None, Quirks::None) => {
+            explain!("using none quirks mode, as the source does not look like an LLVM checkout")
+        }
+    }
     cmd.arg("-S");
     cmd.arg(paths.source.as_os_str());
     cmd.arg("-B");
     cmd.arg(paths.binary.as_os_str());
     cmd.args(["-G", &*configure.generator]);
+    if let Some(instance) = &configure.vs_instance {
+        if !configure.generator.starts_with("Visual Studio") {
+            bail!(
+                "--vs-instance requires a Visual Studio generator, not {:?}",
+                configure.generator
+            );
+        }
+        let mut def = OsString::from("-DCMAKE_GENERATOR_INSTANCE=");
+        def.push(instance);
+        cmd.arg(def);
+    }
+    if let Some(program) = &configure.make_program {
+        let mut def = OsString::from("-DCMAKE_MAKE_PROGRAM=");
+        def.push(program);
+        cmd.arg(def);
+    }
     cmd.arg(format!("-DCMAKE_BUILD_TYPE={}", cli.globals.final_config()));
     if configure.shared_libs {
         cmd.arg("-DBUILD_SHARED_LIBS=On");
@@ -129,44 +271,143 @@ fn plan_configure(
         "-DCMAKE_PREFIX_PATH={}",
         configure.prefix_path.join(";")
     ));
-    cmd.arg("-DCMAKE_INSTALL_PREFIX=dist");
+    if !configure.no_install_prefix {
+        let mut def = OsString::from("-DCMAKE_INSTALL_PREFIX=");
+        def.push(
+            configure
+                .install_prefix
+                .as_deref()
+                .unwrap_or("dist".as_ref()),
+        );
+        cmd.arg(def);
+    }
     cmd.arg("-DCMAKE_EXPORT_COMPILE_COMMANDS=On");
     if let Quirks::Llvm = quirks {
-        cmd.arg("-DLLVM_ENABLE_ASSERTIONS=On");
+        cmd.arg(format!(
+            "-DLLVM_ENABLE_ASSERTIONS={}",
+            if configure.assertions { "On" } else { "Off" }
+        ));
         cmd.arg("-DLLVM_OPTIMIZED_TABLEGEN=On");
         cmd.arg("-DLLVM_USE_SPLIT_DWARF=On");
         if has_command("sphinx-build")? {
+            explain!("enabling sphinx docs, as sphinx-build was found");
             cmd.arg("-DLLVM_ENABLE_SPHINX=On");
+        } else {
+            explain!("not enabling sphinx docs, as sphinx-build was not found");
+        }
+    }
+    match configure.linker.as_deref() {
+        Some("default") => {
+            // User explicitly wants to skip linker selection
+            explain!("using the default linker, as specified explicitly");
         }
-        match configure.linker.as_deref() {
-            Some("default") => {
-                // User explicitly wants to skip linker selection
+        Some(linker) => {
+            explain!("using {linker} linker, as specified explicitly");
+            if !offline() && !has_command(linker)? && !has_command(&format!("ld.{linker}"))? {
+                warning!("could not find the {linker} linker");
             }
-            Some(linker) => {
-                cmd.arg(format!("-DLLVM_USE_LINKER={linker}"));
+            match quirks {
+                Quirks::None => link_flags.push(format!("-fuse-ld={linker}")),
+                Quirks::Llvm => {
+                    cmd.arg(format!("-DLLVM_USE_LINKER={linker}"));
+                }
             }
-            None => {
+        }
+        None => {
+            if let Quirks::Llvm = quirks {
                 if has_command("lld")? && has_cc_flag("-fuse-ld=lld")? {
+                    explain!("using lld linker, as it was found and is supported");
                     cmd.arg("-DLLVM_USE_LINKER=lld");
                 } else if has_command("gold")? && has_cc_flag("-fuse-ld=gold")? {
+                    explain!(
+                        "using gold linker, as it was found and is supported (but lld was not)"
+                    );
                     cmd.arg("-DLLVM_USE_LINKER=gold");
+                } else {
+                    explain!(
+                        "using the default linker, as neither lld nor gold were found and supported"
+                    );
                 }
             }
         }
     }
-    if has_command("ccache")? {
-        match quirks {
-            Quirks::None => {
-                cmd.arg("-DCMAKE_C_COMPILER_LAUNCHER=ccache");
-                cmd.arg("-DCMAKE_CXX_COMPILER_LAUNCHER=ccache");
+    let launchers = match &configure.launcher {
+        Some(launchers) => {
+            let is_cache = |l: &str| {
+                matches!(
+                    Path::new(l).file_stem().and_then(OsStr::to_str),
+                    Some("ccache" | "sccache")
+                )
+            };
+            let mut launchers: Vec<&str> = launchers
+                .iter()
+                .map(String::as_str)
+                .filter(|l| !l.is_empty())
+                .collect();
+            if configure.no_cache && launchers.iter().any(|l| is_cache(l)) {
+                explain!("dropping ccache and sccache from the compiler launchers, as caching is disabled");
+                launchers.retain(|l| !is_cache(l));
             }
-            Quirks::Llvm => {
-                cmd.arg("-DLLVM_CCACHE_BUILD=On");
+            if launchers.is_empty() {
+                explain!("using no compiler launcher, as specified explicitly");
+            } else {
+                explain!(
+                    "using {} compiler launcher, as specified explicitly",
+                    launchers.join(", ")
+                );
             }
+            for launcher in launchers.iter() {
+                if !offline() && !has_command(launcher)? {
+                    warning!("could not find the {launcher} compiler launcher");
+                }
+            }
+            launchers
+        }
+        None if configure.no_cache => {
+            explain!("not enabling ccache, as caching is disabled");
+            vec![]
+        }
+        None if has_command("ccache")? => {
+            explain!("enabling ccache, as it was found");
+            vec!["ccache"]
+        }
+        None => {
+            explain!("not enabling ccache, as it was not found");
+            vec![]
+        }
+    };
+    // A compiler cache directory configured in the environment may not exist yet, e.g. on a fresh
+    // machine, so create it rather than failing the first build.
+    let cache_dirs: Vec<PathBuf> = launchers
+        .iter()
+        .filter_map(|launcher| {
+            let var = match Path::new(launcher).file_stem()?.to_str()? {
+                "ccache" => "CCACHE_DIR",
+                "sccache" => "SCCACHE_DIR",
+                _ => return None,
+            };
+            let dir = PathBuf::from(env::var_os(var).filter(|dir| !dir.is_empty())?);
+            (!dir.exists()).then_some(dir)
+        })
+        .collect();
+    match (quirks, launchers.as_slice()) {
+        (_, []) => {}
+        // LLVM_CCACHE_BUILD can only enable ccache on its own, so anything else falls back to the
+        // generic variables.
+        (Quirks::Llvm, ["ccache"]) => {
+            cmd.arg("-DLLVM_CCACHE_BUILD=On");
+        }
+        (_, launchers) => {
+            let launchers = launchers.join(";");
+            cmd.arg(format!("-DCMAKE_C_COMPILER_LAUNCHER={launchers}"));
+            cmd.arg(format!("-DCMAKE_CXX_COMPILER_LAUNCHER={launchers}"));
         }
     }
     if has_cc_flag("-fcolor-diagnostics")? {
+        explain!("enabling color diagnostics, as the compiler supports them");
         flags.push("-fcolor-diagnostics".into());
+    } else {
+        explain!("not enabling color diagnostics, as the compiler does not support them");
     }
     if configure.san {
         match quirks {
@@ -179,6 +420,27 @@ fn plan_configure(
             }
         }
     }
+    if let Some(lto) = configure.lto {
+        match quirks {
+            Quirks::None => {
+                let flag = match lto {
+                    Lto::Thin => "-flto=thin",
+                    Lto::Full => "-flto",
+                };
+                if has_cc_flag(flag)? {
+                    flags.push(flag.into());
+                } else {
+                    warning!("not enabling LTO, as the compiler does not support {flag}");
+                }
+            }
+            Quirks::Llvm => {
+                cmd.arg(match lto {
+                    Lto::Thin => "-DLLVM_ENABLE_LTO=Thin",
+                    Lto::Full => "-DLLVM_ENABLE_LTO=Full",
+                });
+            }
+        }
+    }
     if let Quirks::Llvm = quirks {
         if configure.expensive_checks {
             cmd.arg("-DLLVM_ENABLE_EXPENSIVE_CHECKS=On");
@@ -201,7 +463,8 @@ fn plan_configure(
         let targets = if let Some(targets) = &configure.targets_to_build {
             let mut t = vec![];
             if !configure.disable_implicit_native {
-                t.push("Native".into());
+                let native = configure.native_target.as_deref().unwrap_or("Native");
+                t.push(native.to_owned());
             }
             t.extend(targets.iter().cloned());
             t.join(";")
@@ -211,247 +474,1831 @@ fn plan_configure(
         cmd.arg(format!("-DLLVM_TARGETS_TO_BUILD={targets}"));
     }
     let flags = flags.join(" ");
-    let maybe_prepend_space = |mut s: String| {
-        if !flags.is_empty() {
-            s.insert(0, ' ');
+    // Our own flags, then those from the environment variable `var`, then the `extra` flags from
+    // the command-line.
+    let lang_flags = |var: &str, extra: &[String]| {
+        let mut all = flags.clone();
+        for part in env::var(var).into_iter().chain(extra.iter().cloned()) {
+            if !all.is_empty() {
+                all.push(' ');
+            }
+            all.push_str(&part);
         }
-        s
+        all
     };
-    let env_cflags = env::var("CFLAGS")
-        .map(maybe_prepend_space)
-        .unwrap_or_default();
-    let env_cxxflags = env::var("CXXFLAGS")
-        .map(maybe_prepend_space)
-        .unwrap_or_default();
-    cmd.arg(format!("-DCMAKE_C_FLAGS={flags}{env_cflags}"));
-    cmd.arg(format!("-DCMAKE_CXX_FLAGS={flags}{env_cxxflags}"));
-    cmd.args(configure.args.as_slice());
-    let mut rm_cmd = process::Command::new("rm");
-    rm_cmd.arg("-rf");
-    let mut cache_path = paths.binary.to_owned();
-    cache_path.push("CMakeCache.txt");
-    rm_cmd.arg(cache_path);
-    let mut files_path = paths.binary.to_owned();
-    files_path.push("CMakeFiles");
-    rm_cmd.arg(files_path);
-    Ok(vec![rm_cmd, cmd])
-}
-
-fn build_cmd(cli: &Cli, paths: Paths) -> process::Command {
+    let c_flags = lang_flags("CFLAGS", &configure.cflags);
+    let cxx_flags = lang_flags("CXXFLAGS", &configure.cxxflags);
+    cmd.arg(format!("-DCMAKE_C_FLAGS={c_flags}"));
+    cmd.arg(format!("-DCMAKE_CXX_FLAGS={cxx_flags}"));
+    if !link_flags.is_empty() {
+        let link_flags = link_flags.join(" ");
+        let env_ldflags = env::var("LDFLAGS")
+            .map(|s| format!(" {s}"))
+            .unwrap_or_default();
+        for kind in ["EXE", "SHARED", "MODULE"] {
+            cmd.arg(format!(
+                "-DCMAKE_{kind}_LINKER_FLAGS={link_flags}{env_ldflags}"
+            ));
+        }
+    }
+    if configure.fresh {
+        require_cmake("--fresh", CMakeVersion(3, 24, 0))?;
+        cmd.arg("--fresh");
+    }
+    if let Some(level) = configure.cmake_log {
+        require_cmake("--cmake-log", CMakeVersion(3, 16, 0))?;
+        let mut arg = OsString::from("--log-level=");
+        arg.push(level);
+        cmd.arg(arg);
+    }
+    if configure.log_context {
+        require_cmake("--log-context", CMakeVersion(3, 17, 0))?;
+        cmd.arg("--log-context");
+    }
+    if configure.no_dev_warnings {
+        cmd.arg("-Wno-dev");
+    }
+    if configure.werror_dev {
+        cmd.arg("-Werror=dev");
+    }
+    // Any variable the user defines explicitly replaces our own definition, rather than relying
+    // on cmake to let the last definition win.
+    let user_args = expand_response_files(&configure.args)?;
+    let user_vars = cmake_defined_vars(&user_args);
+    let mut cmd = retain_args(cmd, |arg| {
+        !cmake_defined_var(arg).is_some_and(|var| user_vars.contains(&var))
+    });
+    cmd.args(user_args.as_slice());
+    if configure.show_cache_vars {
+        let mut defs = cmake_definitions(cmd.get_args());
+        defs.sort();
+        return Ok(vec![PlannedCommand::Print(defs)]);
+    }
+    if configure.diff_config {
+        let defs = cmake_definitions(cmd.get_args());
+        return Ok(vec![PlannedCommand::Print(diff_cache(paths, &defs)?)]);
+    }
+    cmd.args(configure.generator_arg.as_slice());
+    let mut steps = vec![];
+    if !configure.fresh {
+        steps.push(PlannedCommand::Remove(vec![
+            paths.binary.join("CMakeCache.txt"),
+            paths.binary.join("CMakeFiles"),
+        ]));
+    }
+    steps.extend(cache_dirs.into_iter().map(PlannedCommand::CreateDir));
+    steps.push(cmd.into());
+    Ok(steps)
+}
+
+/// Get the variable name from a cmake `<var>[:<type>]=<value>` definition.
+fn cmake_var_name(def: &str) -> Option<&str> {
+    let (var, _) = def.split_once('=')?;
+    Some(var.split_once(':').map_or(var, |(var, _)| var))
+}
+
+/// Get the variable name from a cmake `-D<var>[:<type>]=<value>` argument.
+fn cmake_defined_var(arg: &OsStr) -> Option<&str> {
+    cmake_var_name(arg.to_str()?.strip_prefix("-D")?)
+}
+
+/// Get the variable names defined in cmake arguments, accepting both `-D<var>=<value>` and
+/// `-D <var>=<value>` forms.
+fn cmake_defined_vars(args: &[OsString]) -> Vec<&str> {
+    let mut vars = Vec::new();
+    let mut args = args.iter().map(OsString::as_os_str);
+    while let Some(arg) = args.next() {
+        if arg == "-D" {
+            vars.extend(args.next().and_then(OsStr::to_str).and_then(cmake_var_name));
+        } else {
+            vars.extend(cmake_defined_var(arg));
+        }
+    }
+    vars
+}
+
+/// Get the `<var>[:<type>]=<value>` definitions in cmake arguments, accepting both
+/// `-D<var>=<value>` and `-D <var>=<value>` forms.
+fn cmake_definitions<'a>(args: impl IntoIterator<Item = &'a OsStr>) -> Vec<OsString> {
+    let mut defs = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-D" {
+            defs.extend(args.next().map(OsStr::to_owned));
+        } else if let Some(def) = arg.to_string_lossy().strip_prefix("-D") {
+            defs.push(def.into());
+        }
+    }
+    defs
+}
+
+/// Replace each `@<file>` argument with the arguments in the response file, one per line.
+///
+/// Blank lines are ignored, and response files may themselves refer to other response files,
+/// relative to the directory of the referring file.
+fn expand_response_files(args: &[OsString]) -> Result<Vec<OsString>> {
+    fn expand(
+        args: impl IntoIterator<Item = OsString>,
+        dir: &Path,
+        stack: &mut Vec<PathBuf>,
+        out: &mut Vec<OsString>,
+    ) -> Result<()> {
+        for arg in args {
+            let Some(file) = arg.to_str().and_then(|a| a.strip_prefix('@')) else {
+                out.push(arg);
+                continue;
+            };
+            let path = dir.join(file);
+            let canonical = path
+                .canonicalize()
+                .with_context(|| format!("could not open response file {path:?}"))?;
+            if stack.contains(&canonical) {
+                bail!("response file {path:?} includes itself");
+            }
+            let contents = fs::read_to_string(&canonical)
+                .with_context(|| format!("could not read response file {path:?}"))?;
+            let lines = contents
+                .lines()
+                .map(str::trim_end)
+                .filter(|l| !l.is_empty())
+                .map(OsString::from)
+                .collect::<Vec<_>>();
+            let parent = canonical.parent().unwrap_or(dir).to_owned();
+            stack.push(canonical);
+            expand(lines, &parent, stack, out)?;
+            stack.pop();
+        }
+        Ok(())
+    }
+    let mut out = Vec::new();
+    expand(args.iter().cloned(), Path::new(""), &mut vec![], &mut out)?;
+    Ok(out)
+}
+
+/// Rebuild `cmd` keeping only the arguments for which `f` returns true.
+fn retain_args(cmd: process::Command, f: impl Fn(&OsStr) -> bool) -> process::Command {
+    let mut retained = process::Command::new(cmd.get_program());
+    retained.args(cmd.get_args().filter(|arg| f(arg)));
+    for (key, val) in cmd.get_envs() {
+        match val {
+            Some(val) => retained.env(key, val),
+            None => retained.env_remove(key),
+        };
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        retained.current_dir(dir);
+    }
+    retained
+}
+
+fn build_cmd(cli: &Cli, paths: Paths, cmake_args: &[OsString]) -> process::Command {
     let mut cmd = process::Command::new("cmake");
     cmd.arg("--build");
     cmd.arg(paths.binary);
     cmd.arg("--config");
     cmd.arg(cli.globals.final_config());
+    cmd.args(cmake_args);
     cmd.arg("--");
     cmd
 }
 
-fn plan_build(
-    build: &Build,
-    cli: &Cli,
+fn plan_reconfigure(
+    _reconfigure: &Reconfigure,
+    _cli: &Cli,
     _quirks: Quirks,
     paths: Paths,
 ) -> Result<Vec<process::Command>> {
-    let mut cmd = build_cmd(cli, paths);
-    cmd.args(build.args.as_slice());
+    if !paths.binary.join("CMakeCache.txt").is_file() {
+        bail!(
+            "could not find a CMakeCache.txt in {:?}, has the binary directory been configured?",
+            paths.binary
+        );
+    }
+    let mut cmd = adjust_path(process::Command::new("cmake"));
+    cmd.arg(paths.binary.as_os_str());
     Ok(vec![cmd])
 }
 
-fn plan_lit(lit: &Lit, cli: &Cli, _quirks: Quirks, paths: Paths) -> Result<Vec<process::Command>> {
-    if lit.xfail_export {
-        let mut cmd = process::Command::new("printf");
-        cmd.arg("%s\\n");
-        cmd.arg(format!(
-            "export LIT_XFAIL=\"{}\"",
-            ResultDB::parse(paths)?
-                .tests
-                .iter()
-                .filter(|t| !t.expected)
-                .map(|t| &*t.test_id)
-                .collect::<Vec<_>>()
-                .join(";")
-        ));
-        return Ok(vec![cmd]);
+fn plan_build(
+    build: &Build,
+    cli: &Cli,
+    quirks: Quirks,
+    paths: Paths,
+) -> Result<Vec<PlannedCommand>> {
+    if build.list {
+        let cmd = match cached_var(paths, "CMAKE_GENERATOR")? {
+            Some(generator) if generator.starts_with("Ninja") => {
+                let mut cmd = ninja_cmd(paths);
+                cmd.args(["-t", "targets"]);
+                cmd
+            }
+            Some(_) => {
+                let cmake_args = ["--target".into(), "help".into()];
+                build_cmd(cli, paths, &cmake_args)
+            }
+            None => bail!(
+                "could not find the generator for {:?}, has the binary directory been configured?",
+                paths.binary
+            ),
+        };
+        return Ok(vec![cmd.into()]);
     }
-    if let Some(group) = &lit.group {
-        let mut cmd = build_cmd(cli, paths);
-        cmd.arg(group);
-        if lit.update_resultdb {
-            add_lit_opts_env(&mut cmd, paths)?;
+    let mut cmake_args = build.cmake_arg.clone();
+    let mut targets = build.target.clone();
+    if build.docs {
+        if let Quirks::None = quirks {
+            bail!("--docs requires LLVM quirks mode");
         }
-        return Ok(vec![cmd]);
+        targets.get_or_insert_default().extend(docs_targets(paths)?);
     }
-    let mut args: Vec<PathBuf> = if lit.tests.is_empty() {
-        match ResultDB::parse(paths) {
-            Ok(rdb) => rdb
-                .tests
-                .into_iter()
-                .filter(|t| !t.expected)
-                .take(if lit.first { 1 } else { usize::MAX })
-                .map(|t| t.test_path(paths))
-                .collect(),
-            Err(e) => {
-                eprintln!("Warning: ignoring lit.json: {e:?}");
-                vec![]
-            }
+    if let Some(rev) = &build.since {
+        targets = affected_targets(rev, paths)?;
+    }
+    if let Some(targets) = &targets {
+        if targets.len() > 1 && targets.iter().any(|t| t == "clean") {
+            bail!("the \"clean\" target cannot be combined with other targets");
         }
-    } else {
-        lit.tests.iter().map(Into::into).collect()
-    };
-    args.extend(lit.args.iter().map(Into::into));
-    if args.is_empty() {
-        Ok(vec![])
-    } else if lit.print_only {
-        let mut cmd = process::Command::new("printf");
-        cmd.arg("%s\\n");
-        cmd.args(args);
-        Ok(vec![cmd])
-    } else {
-        let mut lit_path = paths.binary.to_path_buf();
-        lit_path.push("bin/llvm-lit");
-        let mut cmd = process::Command::new(lit_path);
-        if lit.verbose {
-            cmd.env("FILECHECK_OPTS", "--dump-input always");
-            cmd.arg("-a");
-        }
-        cmd.args(args);
-        if lit.update_resultdb {
-            add_lit_opts_env(&mut cmd, paths)?;
+        if cmake_args
+            .iter()
+            .any(|a| a == "-t" || a.to_string_lossy().starts_with("--target"))
+        {
+            bail!("--target cannot be combined with a --cmake-arg specifying a target");
         }
-        Ok(vec![cmd])
+        cmake_args.push("--target".into());
+        cmake_args.extend(targets.iter().map(Into::into));
+    }
+    if build.strict_config {
+        check_cached_config(cli, paths)?;
+    }
+    // Unlike a -j after the --, cmake's own -j works for every generator. The environment also
+    // carries it to any nested cmake --build, such as that of an ExternalProject.
+    let jobs = build.jobs.map(job_count).transpose()?;
+    if let Some(jobs) = jobs {
+        cmake_args.extend(["-j".into(), jobs.to_string().into()]);
+    }
+    let mut cmd = build_cmd(cli, paths, &cmake_args);
+    if let Some(jobs) = jobs {
+        cmd.env("CMAKE_BUILD_PARALLEL_LEVEL", jobs.to_string());
+    }
+    cmd.args(build.args.as_slice());
+    let mut cmds = vec![cmd.into()];
+    if let Some(trace) = &build.trace {
+        if !cached_var(paths, "CMAKE_GENERATOR")?.is_some_and(|g| g.starts_with("Ninja")) {
+            bail!("--trace requires the binary directory to be configured with a Ninja generator");
+        }
+        cmds.push(PlannedCommand::NinjaTrace {
+            log: paths.binary.join(".ninja_log"),
+            into: trace.clone(),
+        });
+    }
+    if build.cache_stats {
+        cmds.push(cache_stats_cmd(paths)?.into());
     }
+    Ok(cmds)
 }
 
-fn plan_activate(
-    _activate: &Activate,
+/// Build the command which prints the statistics of the compiler cache the binary directory was
+/// configured to use as a launcher.
+fn cache_stats_cmd(paths: Paths) -> Result<process::Command> {
+    let mut launchers = vec![];
+    if cached_var(paths, "LLVM_CCACHE_BUILD")?.is_some_and(|v| cmake_truthy(&v)) {
+        launchers.push("ccache".to_owned());
+    }
+    if let Some(cached) = cached_var(paths, "CMAKE_CXX_COMPILER_LAUNCHER")? {
+        launchers.extend(cached.split(';').map(str::to_owned));
+    }
+    for launcher in launchers {
+        let stats_arg = match Path::new(&launcher).file_stem().and_then(OsStr::to_str) {
+            Some("ccache") => "-s",
+            Some("sccache") => "--show-stats",
+            _ => continue,
+        };
+        let mut cmd = process::Command::new(launcher);
+        cmd.arg(stats_arg);
+        return Ok(cmd);
+    }
+    bail!("--cache-stats requires the binary directory to be configured with a ccache or sccache launcher")
+}
+
+/// The number of jobs for -j/--jobs, resolving "auto" to the number of available cores.
+fn job_count(jobs: Jobs) -> Result<NonZeroUsize> {
+    Ok(match jobs {
+        Jobs::Auto => thread::available_parallelism()
+            .context("could not detect the number of available cores for --jobs=auto")?,
+        Jobs::Count(jobs) => jobs,
+    })
+}
+
+fn plan_install(
+    install: &Install,
     cli: &Cli,
-    quirks: Quirks,
+    _quirks: Quirks,
     paths: Paths,
 ) -> Result<Vec<process::Command>> {
-    let mut cmd = process::Command::new("printf");
-    cmd.arg(
-        "CM_SRC=%s CM_BIN=%s CM_CFG=%s CM_QUIRKS=%s;\\n\
-        export CM_SRC CM_BIN CM_CFG CM_QUIRKS;\\n\
-        PATH=\"$CM_BIN/bin:$PATH\";\\n",
-    );
-    cmd.arg(quote(paths.source));
-    cmd.arg(quote(paths.binary));
-    cmd.arg(quote(cli.globals.final_config()));
-    cmd.arg(quote(quirks.as_ref()));
+    let mut cmd = process::Command::new("cmake");
+    cmd.arg("--install");
+    cmd.arg(paths.binary);
+    cmd.arg("--config");
+    cmd.arg(cli.globals.final_config());
+    if let Some(component) = &install.component {
+        cmd.args(["--component", component]);
+    }
+    if install.strip {
+        cmd.arg("--strip");
+    }
+    cmd.args(install.args.as_slice());
     Ok(vec![cmd])
 }
 
-fn plan_deactivate(
-    _deactivate: &Deactivate,
-    _cli: &Cli,
+fn plan_test(
+    test: &Test,
+    cli: &Cli,
     _quirks: Quirks,
-    _paths: Paths,
+    paths: Paths,
 ) -> Result<Vec<process::Command>> {
-    let mut cmd = process::Command::new("printf");
-    cmd.arg(
-        "[ -z \"$CM_BIN\" ] || PATH=\"${PATH/$CM_BIN\\/bin:/}\";\\n\
-        unset -v CM_SRC CM_BIN CM_CFG CM_QUIRKS;\\n",
-    );
+    let mut cmd = process::Command::new("ctest");
+    cmd.arg("--test-dir");
+    cmd.arg(paths.binary);
+    cmd.arg("-C");
+    cmd.arg(cli.globals.final_config());
+    if let Some(regex) = &test.tests_regex {
+        cmd.args(["-R", regex]);
+    }
+    if let Some(label) = &test.label {
+        cmd.args(["-L", label]);
+    }
+    if let Some(jobs) = test.jobs {
+        cmd.arg("-j");
+        cmd.arg(job_count(jobs)?.to_string());
+    }
+    if test.rerun_failed {
+        cmd.arg("--rerun-failed");
+    }
+    cmd.args(test.args.as_slice());
     Ok(vec![cmd])
 }
 
-fn plan(
-    command: &Command,
-    cli: &Cli,
-    quirks: Quirks,
+fn plan_ninja(
+    ninja: &Ninja,
+    _cli: &Cli,
+    _quirks: Quirks,
     paths: Paths,
 ) -> Result<Vec<process::Command>> {
-    match command {
-        Command::Configure(ref c) => plan_configure(c, cli, quirks, paths),
-        Command::Build(ref b) => plan_build(b, cli, quirks, paths),
-        Command::Lit(ref l) => plan_lit(l, cli, quirks, paths),
-        Command::Activate(ref a) => plan_activate(a, cli, quirks, paths),
-        Command::Deactivate(ref d) => plan_deactivate(d, cli, quirks, paths),
+    match cached_var(paths, "CMAKE_GENERATOR")? {
+        Some(generator) if generator.starts_with("Ninja") => {}
+        Some(generator) => bail!(
+            "ninja requires the binary directory to be configured with a Ninja generator, not \
+             {generator:?}"
+        ),
+        None => bail!(
+            "could not find the generator for {:?}, has the binary directory been configured?",
+            paths.binary
+        ),
     }
+    let mut cmd = ninja_cmd(paths);
+    cmd.args(ninja.args.as_slice());
+    Ok(vec![cmd])
 }
 
-fn lit_json_path(paths: Paths) -> Result<PathBuf> {
-    let mut path = paths
-        .binary
-        .canonicalize()
-        .context("could not build lit.json path")?;
-    path.push("lit.json");
-    Ok(path)
+/// Build a command which runs ninja in the binary directory.
+fn ninja_cmd(paths: Paths) -> process::Command {
+    let mut cmd = adjust_path(process::Command::new("ninja"));
+    cmd.arg("-C");
+    cmd.arg(paths.binary);
+    cmd
 }
 
-fn add_lit_opts_env(cmd: &mut process::Command, paths: Paths) -> Result<()> {
-    let mut lit_opts = OsString::from("--resultdb-output ");
-    lit_opts.push(quote(lit_json_path(paths)?.as_os_str()));
-    cmd.env("LIT_OPTS", lit_opts);
-    Ok(())
+/// A build step recorded in a .ninja_log.
+struct NinjaStep<'a> {
+    start: u64,
+    end: u64,
+    hash: &'a str,
+    outputs: Vec<&'a str>,
 }
 
-fn has_command(name: &str) -> Result<bool> {
-    if env::var("CM_TESTING").is_ok() {
-        return Ok(true);
+/// Convert the contents of a .ninja_log to Chrome tracing events, one per step of the most recent
+/// build. Steps are assigned to threads so that no two steps on a thread overlap, approximating
+/// the jobs ninja ran in parallel.
+fn ninja_log_to_trace(log: &str) -> Result<serde_json::Value> {
+    let mut lines = log.lines();
+    if !lines.next().is_some_and(|l| l.starts_with("# ninja log v")) {
+        bail!("not a ninja log");
     }
-    let status = adjust_path(process::Command::new(name))
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status();
-    match status {
-        Ok(_) => Ok(true),
-        Err(e) if e.kind() == NotFound => Ok(false),
-        Err(e) => Result::Err(Error::new(e))
-            .with_context(|| format!("could not test for existence of command `{name}`")),
+    // A step with multiple outputs has a line per output, sharing the times and hash.
+    let mut steps: Vec<NinjaStep> = vec![];
+    let mut last_end = 0;
+    for line in lines.filter(|l| !l.is_empty()) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [start, end, _mtime, output, hash] = fields[..] else {
+            bail!("invalid ninja log entry {line:?}");
+        };
+        let (Ok(start), Ok(end)) = (start.parse(), end.parse()) else {
+            bail!("invalid ninja log entry {line:?}");
+        };
+        // The log is appended to by every build, whose times restart from zero.
+        if end < last_end {
+            steps.clear();
+        }
+        last_end = end;
+        match steps
+            .iter_mut()
+            .find(|s| (s.start, s.end, s.hash) == (start, end, hash))
+        {
+            Some(step) => step.outputs.push(output),
+            None => steps.push(NinjaStep {
+                start,
+                end,
+                hash,
+                outputs: vec![output],
+            }),
+        }
     }
-}
-
-fn has_cc_flag(name: &str) -> Result<bool> {
-    let cc = env::var("CC").unwrap_or("cc".into());
-    let status = adjust_path(process::Command::new(cc))
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .args(["-x", "c", "-", "-o", "-", "-c"])
-        .arg(name)
-        .status();
-    match status {
-        Ok(o) => Ok(o.success()),
-        Err(e) if e.kind() == NotFound => Ok(false),
-        Err(e) => Result::Err(Error::new(e))
-            .with_context(|| format!("could not test for existence of cc flag `{name}`")),
+    steps.sort_by_key(|s| (s.start, u64::MAX - s.end));
+    let mut threads: Vec<u64> = vec![];
+    let mut events = vec![];
+    for NinjaStep {
+        start,
+        end,
+        outputs,
+        ..
+    } in steps
+    {
+        let tid = match threads.iter().position(|&busy_until| busy_until <= start) {
+            Some(tid) => tid,
+            None => {
+                threads.push(0);
+                threads.len() - 1
+            }
+        };
+        threads[tid] = end;
+        events.push(serde_json::json!({
+            "name": outputs.join(", "),
+            "cat": "targets",
+            "ph": "X",
+            "ts": start * 1000,
+            "dur": (end - start) * 1000,
+            "pid": 0,
+            "tid": tid,
+            "args": {},
+        }));
     }
+    Ok(serde_json::Value::Array(events))
 }
 
-fn detect_quirks(cli: &Cli) -> Quirks {
-    let source = cli.globals.source.clone().unwrap_or(".".into());
-    let mut cml = source.clone();
-    cml.push(r"CMakeLists.txt");
-    let mut llvm = source.clone();
-    llvm.push(r"llvm");
-    if !cml.is_file() && llvm.is_dir() {
-        Quirks::Llvm
-    } else {
-        Quirks::None
+/// Bail if the binary directory was configured with a CMAKE_BUILD_TYPE other than the requested
+/// config. A missing cache or empty CMAKE_BUILD_TYPE (as for multi-config generators) passes.
+fn check_cached_config(cli: &Cli, paths: Paths) -> Result<()> {
+    let cached = cached_var(paths, "CMAKE_BUILD_TYPE")?;
+    let requested = cli.globals.final_config();
+    match cached.as_deref() {
+        Some(cached) if !cached.is_empty() && !cached.eq_ignore_ascii_case(requested) => bail!(
+            "{:?} is configured for {cached} rather than {requested}; reconfigure it with \
+             -c/--config={requested}, or use --per-config-dir to keep a binary directory per config",
+            paths.binary
+        ),
+        _ => Ok(()),
     }
 }
 
-fn get_adjusted_path() -> Option<&'static str> {
-    static ADJUSTED_PATH: LazyLock<Option<String>> = LazyLock::new(|| {
-        if let (Ok(path), Ok(cm_bin)) = (env::var("PATH"), env::var("CM_BIN")) {
-            if let Some((first_dir, rest)) = path.split_once(':') {
-                if format!("{cm_bin}/bin") == first_dir {
-                    return Some(rest.to_string());
-                }
+/// Describe how the `<var>[:<type>]=<value>` definitions `defs` differ from the CMakeCache.txt in
+/// the binary directory, as a line per variable sorted by name. Cached variables which are not
+/// defined are only reported as removed if they were originally specified on the command line, as
+/// otherwise they are cmake's (or the project's) own.
+fn diff_cache(paths: Paths, defs: &[OsString]) -> Result<Vec<OsString>> {
+    let cache = paths.binary.join("CMakeCache.txt");
+    let contents = match fs::read_to_string(&cache) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == NotFound => String::new(),
+        Err(e) => return Err(Error::new(e)).with_context(|| format!("could not read {cache:?}")),
+    };
+    // The value of each cached variable, and whether it was specified on the command line, which
+    // cmake records as the help string of any variable not otherwise declared.
+    let mut cached = BTreeMap::new();
+    let mut from_command_line = false;
+    for line in contents.lines() {
+        if let Some(help) = line.strip_prefix("//") {
+            from_command_line = help == "No help, variable specified on the command line.";
+        } else if line.starts_with('#') {
+            continue;
+        } else if let (Some(var), Some((_, value))) = (cmake_var_name(line), line.split_once('=')) {
+            cached.insert(var, (value, from_command_line));
+            from_command_line = false;
+        }
+    }
+    let defs: Vec<_> = defs.iter().map(|def| def.to_string_lossy()).collect();
+    let defined: BTreeMap<&str, &str> = defs
+        .iter()
+        .filter_map(|def| Some((cmake_var_name(def)?, def.split_once('=')?.1)))
+        .collect();
+    let mut lines = vec![];
+    for (&var, &value) in defined.iter() {
+        match cached.get(var) {
+            None => lines.push((var, format!("added {var}={value}"))),
+            Some(&(old, _)) if old != value => {
+                lines.push((var, format!("changed {var}={old} -> {value}")))
             }
+            Some(_) => {}
         }
-        None
-    });
-    ADJUSTED_PATH.as_deref()
+    }
+    for (&var, &(value, from_command_line)) in cached.iter() {
+        if from_command_line && !defined.contains_key(var) {
+            lines.push((var, format!("removed {var}={value}")));
+        }
+    }
+    lines.sort();
+    Ok(lines.into_iter().map(|(_, line)| line.into()).collect())
 }
 
-fn adjust_path(mut cmd: process::Command) -> process::Command {
-    if let Some(adjusted_path) = get_adjusted_path() {
-        cmd.env("PATH", adjusted_path);
-    }
+/// Get the value of the variable `name` from the CMakeCache.txt in the binary directory, or `None`
+/// if the binary directory has not been configured or the variable is not cached.
+fn cached_var(paths: Paths, name: &str) -> Result<Option<String>> {
+    let cache = paths.binary.join("CMakeCache.txt");
+    let contents = match fs::read_to_string(&cache) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == NotFound => return Ok(None),
+        Err(e) => return Err(Error::new(e)).with_context(|| format!("could not read {cache:?}")),
+    };
+    Ok(contents
+        .lines()
+        .find(|line| cmake_var_name(line) == Some(name))
+        .and_then(|line| Some(line.split_once('=')?.1.trim().to_owned())))
+}
+
+/// The sphinx HTML documentation targets for the LLVM projects enabled in the binary directory.
+fn docs_targets(paths: Paths) -> Result<Vec<String>> {
+    let Some(projects) = cached_var(paths, "LLVM_ENABLE_PROJECTS")? else {
+        bail!("--docs requires the binary directory to be configured first");
+    };
+    if !cached_var(paths, "LLVM_ENABLE_SPHINX")?.is_some_and(|v| cmake_truthy(&v)) {
+        bail!("--docs requires LLVM_ENABLE_SPHINX, which configure enables when sphinx-build is found");
+    }
+    let mut targets = vec!["docs-llvm-html".to_owned()];
+    for project in projects
+        .split(';')
+        .filter(|p| !p.is_empty() && *p != "llvm")
+    {
+        targets.push(format!("docs-{project}-html"));
+    }
+    Ok(targets)
+}
+
+/// Whether a cmake boolean value is true, per the cmake if() command.
+fn cmake_truthy(value: &str) -> bool {
+    matches!(
+        value.to_ascii_uppercase().as_str(),
+        "1" | "ON" | "YES" | "TRUE" | "Y"
+    ) || value.parse::<f64>().is_ok_and(|n| n != 0.0)
+}
+
+fn plan_lit(lit: &Lit, cli: &Cli, _quirks: Quirks, paths: Paths) -> Result<Vec<PlannedCommand>> {
+    if lit.xfail_export {
+        let export = format!(
+            "export LIT_XFAIL=\"{}\"",
+            ResultDB::parse(paths)?
+                .tests
+                .iter()
+                .filter(|t| !t.expected)
+                .map(|t| &*t.test_id)
+                .collect::<Vec<_>>()
+                .join(";")
+        );
+        return Ok(vec![PlannedCommand::Print(vec![export.into()])]);
+    }
+    // The tests of a group are only known to the build, so -p/--print-only prints the steps which
+    // would run them instead.
+    let group_steps = |steps: Vec<PlannedCommand>| {
+        if !lit.print_only {
+            return steps;
+        }
+        let lines = steps.iter().map(|step| step.format().into()).collect();
+        vec![PlannedCommand::Print(lines)]
+    };
+    if let Some(group) = &lit.group {
+        let mut cmd = lit_group_cmd(lit, cli, paths, group);
+        if lit.update_resultdb && lit.merge_resultdb {
+            let partial = lit_partial_json_path(paths)?;
+            add_resultdb_output_env(&mut cmd, &partial);
+            let merge = merge_resultdb_cmd(paths, vec![partial])?;
+            return Ok(group_steps(vec![cmd.into(), merge]));
+        } else if lit.update_resultdb {
+            add_lit_opts_env(&mut cmd, paths)?;
+        }
+        return Ok(group_steps(vec![cmd.into()]));
+    }
+    if let Some(groups) = &lit.parallel_groups {
+        let mut cmds = vec![];
+        let mut partials = vec![];
+        for (i, group) in groups.iter().enumerate() {
+            let mut cmd = lit_group_cmd(lit, cli, paths, group);
+            // Concurrent runs cannot share a ResultDB, so each records its own, as a batch does.
+            if lit.update_resultdb {
+                partials.push(add_lit_batch_opts_env(&mut cmd, paths, i + 1)?);
+            }
+            cmds.push(cmd);
+        }
+        let jobs = lit.max_parallel.map_or(cmds.len(), |n| n.get());
+        if !lit.print_only
+            && jobs.min(cmds.len()) > 1
+            && cached_var(paths, "CMAKE_GENERATOR")?.is_some_and(|g| g.starts_with("Ninja"))
+        {
+            warning!(
+                "running up to {} ninja builds in {:?} at once, which can race to rebuild shared \
+                targets and to update the ninja logs; build the test dependencies first, or use \
+                --max-parallel=1",
+                jobs.min(cmds.len()),
+                paths.binary
+            );
+        }
+        let mut steps = vec![];
+        if !partials.is_empty() {
+            // A group which does not get as far as recording results must not have a stale
+            // ResultDB merged in its place.
+            steps.push(PlannedCommand::Remove(partials.clone()));
+        }
+        steps.push(PlannedCommand::Parallel { cmds, jobs });
+        if !partials.is_empty() {
+            steps.push(merge_resultdb_cmd(paths, partials)?);
+        }
+        return Ok(group_steps(steps));
+    }
+    let exclude = lit
+        .exclude
+        .iter()
+        .map(|p| Regex::new(p).with_context(|| format!("invalid --exclude pattern {p:?}")))
+        .collect::<Result<Vec<_>>>()?;
+    let path_map = test_path_map(cli.globals.test_path_map.as_deref())?;
+    let included = |test: &PathBuf| {
+        let test = test.to_string_lossy();
+        !exclude.iter().any(|re| re.is_match(&test))
+    };
+    let mut tests: Vec<PathBuf> = if let Some(rev) = &lit.since {
+        match changed_tests(rev, paths)? {
+            // Unlike an empty ResultDB, which just means there is nothing left to fix, an explicit
+            // selection which matches nothing is most likely a mistake.
+            Some(tests) if tests.is_empty() => bail!("no lit tests changed since {rev:?}"),
+            Some(tests) => tests,
+            None => vec![],
+        }
+    } else if let Some(file) = &lit.from_file {
+        let tests = listed_tests(file, paths, &path_map)?;
+        if tests.is_empty() {
+            bail!("no lit tests listed in {file:?}");
+        }
+        tests
+    } else if lit.all || (lit.tests.is_empty() && (lit.order.is_some() || lit.test_dir.is_some())) {
+        vec![lit
+            .test_dir
+            .clone()
+            .unwrap_or_else(|| paths.binary.join("test"))]
+    } else if lit.tests.is_empty() {
+        if lit.lit.is_some() {
+            bail!("--lit requires the tests to be selected explicitly, e.g. via --test-dir");
+        }
+        match ResultDB::parse(paths) {
+            Ok(rdb) => rdb
+                .tests
+                .into_iter()
+                .filter(|t| !t.expected)
+                .map(|t| t.test_path(paths, &path_map))
+                .filter(included)
+                .filter(|test| {
+                    let missing = lit.skip_missing && !test.exists();
+                    if missing {
+                        warning!("skipping {test:?} from the ResultDB, as it no longer exists");
+                    }
+                    !missing
+                })
+                .take(if lit.first { 1 } else { usize::MAX })
+                .collect(),
+            Err(e) => {
+                warning!("ignoring lit.json: {e:?}");
+                vec![]
+            }
+        }
+    } else if let Some(dir) = &lit.test_dir {
+        let tests: Vec<OsString> = lit.tests.iter().map(|t| dir.join(t).into()).collect();
+        expand_test_globs(&tests)?
+    } else {
+        expand_test_globs(&lit.tests)?
+    };
+    let selected = tests.len();
+    tests.retain(included);
+    // Only reachable with tests selected explicitly, as those from the ResultDB are filtered above.
+    if selected > 0 && tests.is_empty() {
+        bail!("all {selected} of the selected tests were excluded by --exclude");
+    }
+    if tests.is_empty() && lit.args.is_empty() {
+        Ok(vec![])
+    } else if lit.print_only {
+        let mut lines: Vec<OsString> = tests.into_iter().map(Into::into).collect();
+        lines.extend(lit.args.iter().cloned());
+        Ok(vec![PlannedCommand::Print(lines)])
+    } else {
+        let batches = match lit.batch {
+            Some(n) if !tests.is_empty() => tests.chunks(n.get()).collect(),
+            _ => vec![&tests[..]],
+        };
+        let lit_path = match &lit.lit {
+            Some(program) => PathBuf::from(program),
+            None => paths.binary.join(cli.globals.bin_subdir()).join("llvm-lit"),
+        };
+        // A dry run only prints the command, so should not depend on llvm-lit being built yet.
+        if lit.lit.is_none() && !cli.globals.is_dry_run() && !lit_path.exists() {
+            bail!(
+                "llvm-lit not found at {lit_path:?}; run `cm build` first, or build the `check-*` \
+                 target for the tests"
+            );
+        }
+        let mut cmds = vec![];
+        let mut partials = vec![];
+        if lit.update_resultdb && lit.batch.is_some() {
+            // A batch which is skipped must not have a stale ResultDB merged in its place.
+            let stale = (1..=batches.len())
+                .map(|i| lit_batch_json_path(paths, i))
+                .collect::<Result<_>>()?;
+            cmds.push(PlannedCommand::Remove(stale));
+        }
+        for (i, batch) in batches.iter().enumerate() {
+            if batches.len() > 1 {
+                let progress = format!("batch {}/{}", i + 1, batches.len());
+                cmds.push(PlannedCommand::Print(vec![progress.into()]));
+            }
+            let mut cmd = process::Command::new(&lit_path);
+            if lit.verbose {
+                add_filecheck_dump_input(&mut cmd);
+                if !has_lit_show_all(&lit.args) {
+                    cmd.arg("-a");
+                }
+            }
+            if let Some(order) = lit.order {
+                let mut opt = OsString::from("--order=");
+                opt.push(order);
+                cmd.arg(opt);
+            }
+            cmd.args(*batch);
+            cmd.args(lit.args.as_slice());
+            let mut batch_partial = None;
+            if lit.update_resultdb {
+                if lit.batch.is_some() {
+                    batch_partial = Some(add_lit_batch_opts_env(&mut cmd, paths, i + 1)?);
+                } else if lit.merge_resultdb {
+                    let partial = lit_partial_json_path(paths)?;
+                    add_resultdb_output_env(&mut cmd, &partial);
+                    partials.push(partial);
+                } else {
+                    add_lit_opts_env(&mut cmd, paths)?;
+                }
+            }
+            cmds.push(cmd.into());
+            // Merge each batch as soon as it finishes, so interrupting the run still records the
+            // results of the batches which completed.
+            if let Some(partial) = batch_partial {
+                cmds.push(merge_resultdb_cmd(paths, vec![partial])?);
+            }
+        }
+        if lit.merge_resultdb && !partials.is_empty() {
+            cmds.push(merge_resultdb_cmd(paths, partials)?);
+        }
+        Ok(cmds)
+    }
+}
+
+/// Expand any of the lit `tests` which are glob patterns against the filesystem, leaving the rest
+/// as-is. A pattern which matches nothing is an error, rather than being forwarded to llvm-lit.
+fn expand_test_globs(tests: &[OsString]) -> Result<Vec<PathBuf>> {
+    let mut expanded = vec![];
+    for test in tests {
+        let Some(pattern) = test.to_str().filter(|t| t.contains(['*', '?', '['])) else {
+            expanded.push(test.into());
+            continue;
+        };
+        let matches = glob::glob(pattern)
+            .with_context(|| format!("invalid lit test pattern {pattern:?}"))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            bail!("no lit tests match {pattern:?}");
+        }
+        expanded.extend(matches);
+    }
+    Ok(expanded)
+}
+
+/// Build the command which runs the lit test `group` via the build tool.
+fn lit_group_cmd(lit: &Lit, cli: &Cli, paths: Paths, group: &str) -> process::Command {
+    let mut cmd = build_cmd(cli, paths, &[]);
+    cmd.arg(group);
+    // The build tool will not forward any arguments to llvm-lit, so everything must instead be
+    // passed via the environment.
+    if lit.verbose {
+        add_filecheck_dump_input(&mut cmd);
+        if !has_lit_show_all(&lit.args) {
+            append_env_opts(&mut cmd, "LIT_OPTS", "-a".as_ref());
+        }
+    }
+    if let Some(order) = lit.order {
+        let mut opt = OsString::from("--order=");
+        opt.push(order);
+        append_env_opts(&mut cmd, "LIT_OPTS", &opt);
+    }
+    for arg in lit.args.iter() {
+        append_env_opts(&mut cmd, "LIT_OPTS", &quote_sh(arg.as_os_str()));
+    }
+    cmd
+}
+
+fn plan_activate(
+    _activate: &Activate,
+    cli: &Cli,
+    quirks: Quirks,
+    paths: Paths,
+) -> Result<Vec<process::Command>> {
+    let mut cmd = process::Command::new("printf");
+    cmd.arg(
+        "CM_SRC=%s CM_BIN=%s CM_CFG=%s CM_QUIRKS=%s;\\n\
+        export CM_SRC CM_BIN CM_CFG CM_QUIRKS;\\n\
+        PATH=\"$CM_BIN\"/%s:\"$PATH\";\\n",
+    );
+    cmd.arg(quote(paths.source));
+    cmd.arg(quote(paths.binary));
+    cmd.arg(quote(cli.globals.final_config()));
+    cmd.arg(quote(quirks.as_ref()));
+    cmd.arg(quote_bin_subdir(cli.globals.bin_subdir(), "/"));
+    Ok(vec![cmd])
+}
+
+fn plan_deactivate(
+    _deactivate: &Deactivate,
+    cli: &Cli,
+    _quirks: Quirks,
+    _paths: Paths,
+) -> Result<Vec<process::Command>> {
+    let mut cmd = process::Command::new("printf");
+    cmd.arg(
+        "[ -z \"$CM_BIN\" ] || PATH=\"${PATH/\"$CM_BIN\"\\/%s:/}\";\\n\
+        unset -v CM_SRC CM_BIN CM_CFG CM_QUIRKS;\\n",
+    );
+    // Escape the separators, as an unquoted "/" would end the pattern.
+    cmd.arg(quote_bin_subdir(cli.globals.bin_subdir(), "\\/"));
+    Ok(vec![cmd])
+}
+
+/// The summary printed by the prompt command, of the quirks mode and config set by activate, or
+/// `None` when activate has not been run.
+fn prompt_status() -> Option<String> {
+    env::var_os("CM_BIN").filter(|bin| !bin.is_empty())?;
+    let parts: Vec<String> = ["CM_QUIRKS", "CM_CFG"]
+        .into_iter()
+        .filter_map(|var| env::var(var).ok())
+        .filter(|value| !value.is_empty())
+        .collect();
+    Some(format!("[{}]", parts.join(":")))
+}
+
+/// The `PATH` entry which activate prepends and deactivate removes, given the binary directory
+/// `bin` and the `subdir` of it containing executables.
+///
+/// The scripts spell the same entry for bash and zsh, where `PATH` is ':'-separated on every
+/// platform.
+fn activate_path_entry(bin: &Path, subdir: &Path) -> PathBuf {
+    bin.join(subdir)
+}
+
+/// Quote `subdir` (see --bin-subdir) for the activate and deactivate scripts, one component at a
+/// time so that the `separator` between them can be escaped where bash requires it.
+///
+/// It is passed to printf as an argument rather than in the format, so it cannot be mistaken for
+/// a conversion specification.
+fn quote_bin_subdir(subdir: &Path, separator: &str) -> OsString {
+    let mut out = OsString::new();
+    for (i, component) in subdir.iter().enumerate() {
+        if i > 0 {
+            out.push(separator);
+        }
+        out.push(quote(component));
+    }
+    out
+}
+
+fn plan_doctor(
+    _doctor: &Doctor,
+    _cli: &Cli,
+    quirks: Quirks,
+    _paths: Paths,
+) -> Result<Vec<PlannedCommand>> {
+    let check = |available: bool, name: &str, usage: &str| {
+        let mark = if available { 'x' } else { ' ' };
+        OsString::from(format!("[{mark}] {name}: {usage}"))
+    };
+    let mut lines = vec![OsString::from(format!(
+        "quirks mode: {}",
+        quirks.as_ref().to_string_lossy()
+    ))];
+    for (name, usage) in [
+        ("cmake", "required to configure and build"),
+        ("ninja", "required by the default generator"),
+        ("ccache", "used as the compiler launcher"),
+        ("sphinx-build", "used to build LLVM documentation"),
+        (
+            "lld",
+            "preferred linker for LLVM, if cc supports -fuse-ld=lld",
+        ),
+        (
+            "gold",
+            "fallback linker for LLVM, if cc supports -fuse-ld=gold",
+        ),
+        ("git", "used to find changed tests for lit --since"),
+    ] {
+        lines.push(check(has_command(name)?, name, usage));
+    }
+    for (flag, usage) in [
+        ("-fuse-ld=lld", "required to use lld"),
+        ("-fuse-ld=gold", "required to use gold"),
+        ("-fcolor-diagnostics", "used to force color diagnostics"),
+    ] {
+        lines.push(check(has_cc_flag(flag)?, &format!("cc {flag}"), usage));
+    }
+    Ok(vec![PlannedCommand::Print(lines)])
+}
+
+fn plan_compile_db(
+    _compile_db: &CompileDb,
+    _cli: &Cli,
+    _quirks: Quirks,
+    paths: Paths,
+) -> Result<Vec<PlannedCommand>> {
+    let path = paths.binary.join("compile_commands.json");
+    if !path.is_file() {
+        bail!("could not find {path:?}, has the binary directory been configured?");
+    }
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("could not canonicalize {path:?}"))?;
+    Ok(vec![PlannedCommand::Print(vec![path.into()])])
+}
+
+fn plan_uninstall(
+    _uninstall: &Uninstall,
+    _cli: &Cli,
+    _quirks: Quirks,
+    paths: Paths,
+) -> Result<Vec<PlannedCommand>> {
+    let manifest = paths.binary.join("install_manifest.txt");
+    if !manifest.is_file() {
+        bail!("could not find {manifest:?}, has the binary directory been installed?");
+    }
+    let contents = fs::read_to_string(&manifest)
+        .with_context(|| format!("could not read install manifest {manifest:?}"))?;
+    let files = contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect();
+    Ok(vec![PlannedCommand::Uninstall(files)])
+}
+
+fn plan(command: &Command, cli: &Cli, quirks: Quirks, paths: Paths) -> Result<Vec<PlannedCommand>> {
+    let cmds = match command {
+        Command::Configure(ref c) => return plan_configure(c, cli, quirks, paths),
+        Command::Reconfigure(ref r) => plan_reconfigure(r, cli, quirks, paths)?,
+        Command::Build(ref b) => return plan_build(b, cli, quirks, paths),
+        Command::Install(ref i) => plan_install(i, cli, quirks, paths)?,
+        Command::Test(ref t) => plan_test(t, cli, quirks, paths)?,
+        Command::Ninja(ref n) => plan_ninja(n, cli, quirks, paths)?,
+        Command::Lit(ref l) => return plan_lit(l, cli, quirks, paths),
+        Command::Activate(ref a) => plan_activate(a, cli, quirks, paths)?,
+        Command::Deactivate(ref d) => plan_deactivate(d, cli, quirks, paths)?,
+        Command::Doctor(ref d) => return plan_doctor(d, cli, quirks, paths),
+        Command::CompileDb(ref c) => return plan_compile_db(c, cli, quirks, paths),
+        Command::Uninstall(ref u) => return plan_uninstall(u, cli, quirks, paths),
+        Command::Completions(_) | Command::Man(_) | Command::Prompt(_) => {
+            unreachable!("completions, manpages, and the prompt are printed before planning")
+        }
+    };
+    Ok(cmds.into_iter().map(PlannedCommand::from).collect())
+}
+
+/// A step of a plan, either an external command or an action performed in-process.
+enum PlannedCommand {
+    Spawn(process::Command),
+    /// The --post hook, which is told the status of the plan when it is run.
+    PostHook(process::Command),
+    /// Remove files or directories (recursively), ignoring any which do not exist. This is
+    /// best-effort: any other failure is reported as a warning rather than failing the plan, as
+    /// the removal is only cleanup ahead of the next step.
+    Remove(Vec<PathBuf>),
+    /// Remove the installed files listed in an install manifest, reporting how many were removed.
+    /// Files which do not exist are counted as already absent, and any other failure fails the
+    /// step once every file has been tried.
+    Uninstall(Vec<PathBuf>),
+    /// Convert the ninja log `log` to a Chrome tracing profile written to `into`.
+    NinjaTrace {
+        log: PathBuf,
+        into: PathBuf,
+    },
+    /// Create a directory (and any missing parents). Like `Remove`, this is best-effort, and any
+    /// failure is reported as a warning.
+    CreateDir(PathBuf),
+    /// Run the external commands concurrently, at most `jobs` at once, succeeding only if every
+    /// command succeeds. Every command is run even when one fails, as they are independent.
+    Parallel {
+        cmds: Vec<process::Command>,
+        jobs: usize,
+    },
+    /// Print each of the lines to stdout, without depending on a printf command.
+    Print(Vec<OsString>),
+    /// Merge the ResultDBs `from` into the ResultDB `into`, in order. This runs even when an
+    /// earlier step fails, as failing tests are exactly what the ResultDB should record.
+    MergeResultDB {
+        from: Vec<PathBuf>,
+        into: PathBuf,
+    },
+}
+
+impl From<process::Command> for PlannedCommand {
+    fn from(cmd: process::Command) -> Self {
+        PlannedCommand::Spawn(cmd)
+    }
+}
+
+impl PlannedCommand {
+    /// Format the step for the dry-run output.
+    fn format(&self) -> String {
+        match self {
+            PlannedCommand::Spawn(cmd) | PlannedCommand::PostHook(cmd) => format_cmd(cmd),
+            PlannedCommand::Remove(paths) => {
+                let mut quoted = vec!["remove".to_owned()];
+                quoted.extend(
+                    paths
+                        .iter()
+                        .map(|path| quote(path.as_path()).to_string_lossy().into_owned()),
+                );
+                quoted.join(" ")
+            }
+            PlannedCommand::Uninstall(files) => {
+                let mut quoted = vec!["uninstall".to_owned()];
+                quoted.extend(
+                    files
+                        .iter()
+                        .map(|path| quote(path.as_path()).to_string_lossy().into_owned()),
+                );
+                quoted.join(" ")
+            }
+            PlannedCommand::CreateDir(path) => {
+                format!("mkdir {}", quote(path.as_path()).to_string_lossy())
+            }
+            PlannedCommand::Parallel { cmds, jobs } => {
+                let mut lines = vec![format!("parallel (at most {jobs} at once)")];
+                lines.extend(cmds.iter().map(|cmd| format!("  {}", format_cmd(cmd))));
+                lines.join("\n")
+            }
+            PlannedCommand::Print(lines) => {
+                let mut quoted = vec!["print".to_owned()];
+                quoted.extend(
+                    lines
+                        .iter()
+                        .map(|line| quote(line).to_string_lossy().into_owned()),
+                );
+                quoted.join(" ")
+            }
+            PlannedCommand::NinjaTrace { log, into } => [
+                "trace".to_owned(),
+                quote(log.as_path()).to_string_lossy().into_owned(),
+                "into".to_owned(),
+                quote(into.as_path()).to_string_lossy().into_owned(),
+            ]
+            .join(" "),
+            PlannedCommand::MergeResultDB { from, into } => {
+                let mut quoted = vec!["merge".to_owned()];
+                quoted.extend(
+                    from.iter()
+                        .map(|path| quote(path.as_path()).to_string_lossy().into_owned()),
+                );
+                quoted.push("into".to_owned());
+                quoted.push(quote(into.as_path()).to_string_lossy().into_owned());
+                quoted.join(" ")
+            }
+        }
+    }
+
+    /// Whether the step should still be performed after an earlier step has failed.
+    fn always_run(&self) -> bool {
+        matches!(self, PlannedCommand::MergeResultDB { .. })
+    }
+
+    /// Perform the step, returning the exit status for external commands.
+    fn run(&mut self) -> Result<Option<ExitStatus>> {
+        match self {
+            PlannedCommand::Spawn(cmd) | PlannedCommand::PostHook(cmd) => Ok(Some(cmd.status()?)),
+            PlannedCommand::Remove(paths) => {
+                for path in paths.iter() {
+                    if let Err(err) = remove_path(path) {
+                        warning!("could not remove {path:?}: {err}");
+                    }
+                }
+                Ok(None)
+            }
+            PlannedCommand::Uninstall(files) => {
+                let (mut removed, mut absent, mut failed) = (0, 0, 0);
+                for file in files.iter() {
+                    match fs::remove_file(file) {
+                        Ok(()) => removed += 1,
+                        Err(err) if err.kind() == NotFound => absent += 1,
+                        Err(err) => {
+                            warning!("could not remove {file:?}: {err}");
+                            failed += 1;
+                        }
+                    }
+                }
+                let files_noun = |n: usize| if n == 1 { "file" } else { "files" };
+                println!(
+                    "removed {removed} installed {} ({absent} already absent)",
+                    files_noun(removed)
+                );
+                if failed > 0 {
+                    bail!(
+                        "could not remove {failed} of {} installed {}",
+                        files.len(),
+                        files_noun(files.len())
+                    );
+                }
+                Ok(None)
+            }
+            PlannedCommand::CreateDir(path) => {
+                if let Err(err) = fs::create_dir_all(&*path) {
+                    warning!("could not create {path:?}: {err}");
+                }
+                Ok(None)
+            }
+            PlannedCommand::Parallel { cmds, jobs } => {
+                let count = cmds.len();
+                let pending = Mutex::new(cmds.iter_mut().enumerate());
+                let finished = Mutex::new(vec![]);
+                thread::scope(|scope| {
+                    for _ in 0..(*jobs).min(count) {
+                        scope.spawn(|| loop {
+                            let Some((i, cmd)) = pending.lock().unwrap().next() else {
+                                break;
+                            };
+                            let status = cmd.status();
+                            finished.lock().unwrap().push((i, status));
+                        });
+                    }
+                });
+                let mut finished = finished.into_inner().unwrap();
+                finished.sort_by_key(|(i, _)| *i);
+                let mut result = None;
+                for (_, status) in finished {
+                    let status = status?;
+                    if result.is_none_or(|r: ExitStatus| r.success()) {
+                        result = Some(status);
+                    }
+                }
+                Ok(result)
+            }
+            PlannedCommand::Print(lines) => {
+                let mut stdout = io::stdout().lock();
+                for line in lines.iter() {
+                    stdout
+                        .write_all(line.as_encoded_bytes())
+                        .and_then(|()| stdout.write_all(b"\n"))
+                        .context("could not write to stdout")?;
+                }
+                Ok(None)
+            }
+            PlannedCommand::NinjaTrace { log, into } => {
+                let contents = match fs::read_to_string(&*log) {
+                    Ok(contents) => contents,
+                    Err(e) if e.kind() == NotFound => {
+                        bail!("could not find {log:?}, so ninja did not record the build")
+                    }
+                    Err(e) => {
+                        return Err(Error::new(e))
+                            .with_context(|| format!("could not read {log:?}"))
+                    }
+                };
+                let trace = ninja_log_to_trace(&contents)
+                    .with_context(|| format!("could not convert {log:?}"))?;
+                fs::write(&*into, trace.to_string())
+                    .with_context(|| format!("could not write {into:?}"))?;
+                Ok(None)
+            }
+            PlannedCommand::MergeResultDB { from, into } => {
+                for path in from.iter() {
+                    merge_resultdb(path, into)?;
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Remove a file or directory (recursively), succeeding if it does not exist.
+fn remove_path(path: &Path) -> io::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(err) => Err(err),
+    };
+    match result {
+        Err(err) if err.kind() == NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Read the KEY=VALUE assignments in the --env-file `path`, skipping blank lines and '#'
+/// comments.
+fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("could not read env file {path:?}"))?;
+    let mut vars = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            bail!(
+                "invalid line {} in env file {path:?}, expected KEY=VALUE",
+                i + 1
+            );
+        };
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+            .unwrap_or(value);
+        vars.push((key.trim().to_owned(), value.to_owned()));
+    }
+    Ok(vars)
+}
+
+/// Set the --env-file `vars` on `cmd`, except for any variable cm already sets on it.
+fn add_env_file_vars(cmd: &mut process::Command, vars: &[(String, String)]) {
+    for (key, value) in vars {
+        if !cmd.get_envs().any(|(k, _)| k == OsStr::new(key)) {
+            cmd.env(key, value);
+        }
+    }
+}
+
+/// Stop `cmd` inheriting our environment for --isolate-env, other than PATH and HOME, keeping any
+/// variables set for it explicitly.
+fn clear_env(cmd: &mut process::Command) {
+    let vars: Vec<_> = cmd
+        .get_envs()
+        .map(|(key, val)| (key.to_owned(), val.map(OsStr::to_owned)))
+        .collect();
+    cmd.env_clear();
+    for key in ["PATH", "HOME"] {
+        if let Some(val) = env::var_os(key) {
+            cmd.env(key, val);
+        }
+    }
+    for (key, val) in vars {
+        match val {
+            Some(val) => cmd.env(key, val),
+            None => cmd.env_remove(key),
+        };
+    }
+}
+
+/// Parse the --test-path-map rules, given as `PATTERN=REPLACEMENT` separated by ';'.
+fn test_path_map(map: Option<&str>) -> Result<Vec<(Regex, String)>> {
+    let Some(map) = map else {
+        return Ok(vec![]);
+    };
+    map.split(';')
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let Some((find, replace)) = rule.rsplit_once('=') else {
+                bail!("invalid --test-path-map rule {rule:?}, expected PATTERN=REPLACEMENT");
+            };
+            let find = Regex::new(find)
+                .with_context(|| format!("invalid --test-path-map pattern {find:?}"))?;
+            Ok((find, replace.to_owned()))
+        })
+        .collect()
+}
+
+/// Read the lit tests listed in `file`, one test path or ID per line, skipping blank lines and
+/// '#' comments. IDs are mapped to paths as for the ResultDB.
+fn listed_tests(file: &Path, paths: Paths, path_map: &[(Regex, String)]) -> Result<Vec<PathBuf>> {
+    let contents =
+        fs::read_to_string(file).with_context(|| format!("could not read test list {file:?}"))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            ResultDBTest {
+                expected: false,
+                test_id: line.to_owned(),
+            }
+            .test_path(paths, path_map)
+        })
+        .collect())
+}
+
+/// Find the lit tests in the source which have changed since the git revision `rev`, or `None` if
+/// the source is not a git repository.
+fn changed_tests(rev: &str, paths: Paths) -> Result<Option<Vec<PathBuf>>> {
+    let Some(toplevel) = git_output(paths, &["rev-parse", "--show-toplevel"])? else {
+        warning!(
+            "ignoring --since: {:?} is not a git repository",
+            paths.source
+        );
+        return Ok(None);
+    };
+    let toplevel = PathBuf::from(toplevel.trim_end());
+    let changed = git_output(paths, &["diff", "--name-only", rev, "--"])?
+        .with_context(|| format!("could not list files changed since {rev:?}"))?;
+    Ok(changed
+        .lines()
+        .map(Path::new)
+        .filter(|p| {
+            let mut components = p.components().map(|c| c.as_os_str());
+            components.clone().any(|c| c == "test") && !components.any(|c| c == "Inputs")
+        })
+        .map(|p| toplevel.join(p))
+        .filter(|p| p.is_file())
+        .collect::<Vec<_>>()
+        .into())
+}
+
+/// Find the targets which compile the source files changed since the git revision `rev`, or `None`
+/// (with a warning) if the changes cannot all be mapped to targets, so everything should be built.
+fn affected_targets(rev: &str, paths: Paths) -> Result<Option<Vec<String>>> {
+    // Changes to any other files cannot affect the build.
+    const BUILD_EXTENSIONS: &[&str] = &[
+        "c", "cc", "cpp", "cxx", "h", "hh", "hpp", "inc", "def", "td", "cmake",
+    ];
+    let fallback = |reason: &str| {
+        warning!("building everything for --since, as {reason}");
+        Ok(None)
+    };
+    let Some(toplevel) = git_output(paths, &["rev-parse", "--show-toplevel"])? else {
+        return fallback(&format!("{:?} is not a git repository", paths.source));
+    };
+    let toplevel = PathBuf::from(toplevel.trim_end());
+    let changed = git_output(paths, &["diff", "--name-only", rev, "--"])?
+        .with_context(|| format!("could not list files changed since {rev:?}"))?;
+    let db_path = paths.binary.join("compile_commands.json");
+    let db: Vec<serde_json::Value> = match fs::read_to_string(&db_path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("could not parse {db_path:?}"))?,
+        Err(e) if e.kind() == NotFound => {
+            return fallback(&format!("{db_path:?} does not exist"));
+        }
+        Err(e) => return Err(Error::new(e)).with_context(|| format!("could not read {db_path:?}")),
+    };
+    // The target of each compiled file, from the CMakeFiles/<target>.dir component of its output.
+    let mut file_targets = BTreeMap::new();
+    for entry in db.iter() {
+        let field = |name| entry.get(name).and_then(serde_json::Value::as_str);
+        let (Some(dir), Some(file), Some(output)) =
+            (field("directory"), field("file"), field("output"))
+        else {
+            continue;
+        };
+        let components: Vec<_> = Path::new(output).components().collect();
+        let target = components.windows(2).find_map(|pair| match pair {
+            [Component::Normal(files), Component::Normal(dir)] if *files == "CMakeFiles" => {
+                dir.to_str()?.strip_suffix(".dir")
+            }
+            _ => None,
+        });
+        if let Some(target) = target {
+            let file = paths.binary.join(dir).join(file);
+            file_targets.insert(file.canonicalize().unwrap_or(file), target);
+        }
+    }
+    let mut targets = vec![];
+    for path in changed.lines().map(|p| toplevel.join(p)) {
+        let affects_build = path
+            .file_name()
+            .is_some_and(|name| name == "CMakeLists.txt")
+            || path
+                .extension()
+                .and_then(OsStr::to_str)
+                .is_some_and(|ext| BUILD_EXTENSIONS.contains(&ext));
+        if !affects_build {
+            continue;
+        }
+        match file_targets.get(&path.canonicalize().unwrap_or(path.clone())) {
+            Some(target) if !targets.contains(target) => targets.push(*target),
+            Some(_) => {}
+            None => return fallback(&format!("{path:?} cannot be mapped to a target")),
+        }
+    }
+    if targets.is_empty() {
+        return fallback(&format!("no source files changed since {rev:?}"));
+    }
+    Ok(Some(targets.into_iter().map(str::to_owned).collect()))
+}
+
+/// Run git in the source directory, returning its stdout or `None` if it is not available or
+/// fails.
+fn git_output(paths: Paths, args: &[&str]) -> Result<Option<String>> {
+    let output = adjust_path(process::Command::new("git"))
+        .arg("-C")
+        .arg(paths.source)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    match output {
+        Ok(o) if o.status.success() => Ok(Some(String::from_utf8_lossy(&o.stdout).into_owned())),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == NotFound => Ok(None),
+        Err(e) => Result::Err(Error::new(e)).context("could not run git"),
+    }
+}
+
+fn lit_json_path(paths: Paths) -> Result<PathBuf> {
+    let mut path = paths
+        .binary
+        .canonicalize()
+        .context("could not build lit.json path")?;
+    path.push("lit.json");
+    Ok(path)
+}
+
+fn add_lit_opts_env(cmd: &mut process::Command, paths: Paths) -> Result<()> {
+    add_resultdb_output_env(cmd, &lit_json_path(paths)?);
+    Ok(())
+}
+
+/// Like `add_lit_opts_env`, but for the ResultDB of a single batch of a lit --batch run (or group
+/// of a --parallel-groups run), which is merged into the ResultDB once it finishes. Returns the
+/// path of the batch ResultDB.
+fn add_lit_batch_opts_env(
+    cmd: &mut process::Command,
+    paths: Paths,
+    batch: usize,
+) -> Result<PathBuf> {
+    let path = lit_batch_json_path(paths, batch)?;
+    add_resultdb_output_env(cmd, &path);
+    Ok(path)
+}
+
+/// The path llvm-lit records the results of a single batch to, see `add_lit_batch_opts_env`.
+fn lit_batch_json_path(paths: Paths, batch: usize) -> Result<PathBuf> {
+    let mut path = lit_json_path(paths)?;
+    path.set_file_name(format!("lit.batch-{batch}.json"));
+    Ok(path)
+}
+
+/// The path llvm-lit records results to for lit --merge-resultdb, before they are merged.
+fn lit_partial_json_path(paths: Paths) -> Result<PathBuf> {
+    let mut path = lit_json_path(paths)?;
+    path.set_file_name("lit.partial.json");
+    Ok(path)
+}
+
+fn merge_resultdb_cmd(paths: Paths, from: Vec<PathBuf>) -> Result<PlannedCommand> {
+    Ok(PlannedCommand::MergeResultDB {
+        from,
+        into: lit_json_path(paths)?,
+    })
+}
+
+/// Merge the tests of the ResultDB at `from` into the ResultDB at `into`, replacing the results of
+/// any tests in both, and then remove `from`.
+///
+/// Entries are merged as raw JSON, so any fields cm does not otherwise use are preserved.
+fn merge_resultdb(from: &Path, into: &Path) -> Result<()> {
+    fn read(path: &Path) -> Result<Option<serde_json::Value>> {
+        let file = match File::open(path) {
+            Err(err) if err.kind() == NotFound => return Ok(None),
+            file => file.with_context(|| format!("could not open {path:?}"))?,
+        };
+        let value = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("could not parse {path:?}"))?;
+        Ok(Some(value))
+    }
+    fn tests<'a>(
+        rdb: &'a mut serde_json::Value,
+        path: &Path,
+    ) -> Result<&'a mut Vec<serde_json::Value>> {
+        rdb.get_mut("tests")
+            .and_then(serde_json::Value::as_array_mut)
+            .with_context(|| format!("could not find tests in {path:?}"))
+    }
+    // If llvm-lit did not get as far as recording any results there is nothing to merge.
+    let Some(mut partial) = read(from)? else {
+        return Ok(());
+    };
+    let merged = match read(into)? {
+        None => partial,
+        Some(mut rdb) => {
+            let existing = tests(&mut rdb, into)?;
+            for test in tests(&mut partial, from)?.drain(..) {
+                let test_id = test.get("testId");
+                match existing.iter_mut().find(|t| t.get("testId") == test_id) {
+                    Some(t) => *t = test,
+                    None => existing.push(test),
+                }
+            }
+            rdb
+        }
+    };
+    let file = File::create(into).with_context(|| format!("could not create {into:?}"))?;
+    serde_json::to_writer_pretty(io::BufWriter::new(file), &merged)
+        .with_context(|| format!("could not write {into:?}"))?;
+    fs::remove_file(from).with_context(|| format!("could not remove {from:?}"))
+}
+
+fn add_resultdb_output_env(cmd: &mut process::Command, path: &Path) {
+    let mut lit_opts = OsString::from("--resultdb-output ");
+    lit_opts.push(quote_sh(path.as_os_str()));
+    append_env_opts(cmd, "LIT_OPTS", &lit_opts);
+}
+
+/// Append space-separated `opts` to the environment variable `key` for `cmd`, preserving any
+/// value already set on `cmd` or inherited from our own environment.
+fn append_env_opts(cmd: &mut process::Command, key: &str, opts: &OsStr) {
+    let existing = cmd
+        .get_envs()
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.map(OsStr::to_owned))
+        .unwrap_or_else(|| env::var_os(key));
+    let mut value = match existing {
+        Some(mut v) if !v.is_empty() => {
+            v.push(" ");
+            v
+        }
+        _ => OsString::new(),
+    };
+    value.push(opts);
+    cmd.env(key, value);
+}
+
+/// Ask FileCheck to always dump its input, unless the user already chose a --dump-input mode via
+/// FILECHECK_OPTS, in which case theirs takes precedence.
+fn add_filecheck_dump_input(cmd: &mut process::Command) {
+    let user_choice =
+        env::var_os("FILECHECK_OPTS").is_some_and(|v| v.to_string_lossy().contains("--dump-input"));
+    if !user_choice {
+        append_env_opts(cmd, "FILECHECK_OPTS", "--dump-input always".as_ref());
+    }
+}
+
+/// Whether the trailing llvm-lit arguments already ask it to show all test output.
+fn has_lit_show_all(args: &[OsString]) -> bool {
+    args.iter().any(|a| a == "-a" || a == "--show-all")
+}
+
+/// A cmake version, as (major, minor, patch).
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct CMakeVersion(u32, u32, u32);
+
+impl fmt::Display for CMakeVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+impl CMakeVersion {
+    /// Parse the output of `cmake --version`, e.g. "cmake version 3.28.3".
+    fn parse(output: &str) -> Option<CMakeVersion> {
+        let version = output.lines().next()?.strip_prefix("cmake version ")?;
+        let mut parts = version.split('.').map(|p| {
+            let digits = p.bytes().take_while(u8::is_ascii_digit).count();
+            p[..digits].parse::<u32>().ok()
+        });
+        let major = parts.next().flatten()?;
+        let minor = parts.next().flatten().unwrap_or(0);
+        let patch = parts.next().flatten().unwrap_or(0);
+        Some(CMakeVersion(major, minor, patch))
+    }
+}
+
+/// Get the version of the cmake we will run, which is only queried once.
+fn cmake_version() -> Result<CMakeVersion> {
+    static CMAKE_VERSION: OnceLock<CMakeVersion> = OnceLock::new();
+    if let Some(version) = CMAKE_VERSION.get() {
+        return Ok(*version);
+    }
+    let version = if env::var("CM_TESTING").is_ok() {
+        CMakeVersion(u32::MAX, 0, 0)
+    } else {
+        let output = adjust_path(process::Command::new("cmake"))
+            .arg("--version")
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .context("could not run `cmake --version`")?;
+        CMakeVersion::parse(&String::from_utf8_lossy(&output.stdout))
+            .context("could not parse the output of `cmake --version`")?
+    };
+    Ok(*CMAKE_VERSION.get_or_init(|| version))
+}
+
+/// Fail with an error describing `feature` unless cmake is at least version `min`.
+fn require_cmake(feature: &str, min: CMakeVersion) -> Result<()> {
+    let found = cmake_version()?;
+    if found < min {
+        bail!("{feature} requires cmake >= {min}, found {found}");
+    }
+    Ok(())
+}
+
+/// Run a capability probe, retrying with backoff if it fails to spawn for any reason other than
+/// the command not existing, as spawning can fail transiently (e.g. EAGAIN) on a busy machine.
+fn probe_status(cmd: &mut process::Command) -> io::Result<ExitStatus> {
+    const ATTEMPTS: u32 = 3;
+    let mut attempt = 1;
+    loop {
+        match cmd.status() {
+            Err(e) if e.kind() != NotFound && attempt < ATTEMPTS => {
+                warning!(
+                    "retrying `{}` after error: {e}",
+                    cmd.get_program().to_string_lossy()
+                );
+                thread::sleep(Duration::from_millis(100 << attempt));
+                attempt += 1;
+            }
+            status => return status,
+        }
+    }
+}
+
+fn has_command(name: &str) -> Result<bool> {
+    if offline() {
+        return Ok(false);
+    }
+    if env::var("CM_TESTING").is_ok() {
+        return Ok(true);
+    }
+    let mut cmd = adjust_path(process::Command::new(name));
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    let found = match probe_status(&mut cmd) {
+        Ok(_) => true,
+        Err(e) if e.kind() == NotFound => false,
+        Err(e) => {
+            return Result::Err(Error::new(e))
+                .with_context(|| format!("could not test for existence of command `{name}`"))
+        }
+    };
+    debug!("has_command({name:?}): {found}");
+    Ok(found)
+}
+
+fn has_cc_flag(name: &str) -> Result<bool> {
+    if offline() {
+        return Ok(false);
+    }
+    if env::var("CM_TESTING").is_ok() {
+        return Ok(true);
+    }
+    let cc = env::var("CC").unwrap_or("cc".into());
+    let mut cmd = adjust_path(process::Command::new(cc));
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .args(["-x", "c", "-", "-o", "-", "-c"])
+        .arg(name);
+    let supported = match probe_status(&mut cmd) {
+        Ok(o) => o.success(),
+        Err(e) if e.kind() == NotFound => false,
+        Err(e) => {
+            return Result::Err(Error::new(e))
+                .with_context(|| format!("could not test for existence of cc flag `{name}`"))
+        }
+    };
+    debug!(
+        "has_cc_flag({name:?}) with {:?}: {supported}",
+        cmd.get_program()
+    );
+    Ok(supported)
+}
+
+/// The project-specific defaults implied by a `Project`, which can be mixed with an explicit
+/// `Quirks` to override only the quirks mode.
+#[derive(Clone, Copy)]
+struct Profile {
+    quirks: Quirks,
+    /// Default source directory, relative to the current directory.
+    source: &'static str,
+}
+
+fn profile(project: Project) -> Profile {
+    match project {
+        Project::Generic => Profile {
+            quirks: Quirks::None,
+            source: ".",
+        },
+        Project::Llvm => Profile {
+            quirks: Quirks::Llvm,
+            source: "llvm",
+        },
+    }
+}
+
+/// The project implied by the (explicit or detected) quirks mode, for when none is specified.
+fn implied_project(cli: &Cli) -> Project {
+    match cli.globals.quirks.unwrap_or_else(|| detect_quirks(cli)) {
+        Quirks::None => Project::Generic,
+        Quirks::Llvm => Project::Llvm,
+    }
+}
+
+/// Detect the quirks mode from the source directory. Each source directory is only checked once,
+/// as the quirks mode is needed at several points while planning.
+fn detect_quirks(cli: &Cli) -> Quirks {
+    static QUIRKS: Mutex<BTreeMap<PathBuf, Quirks>> = Mutex::new(BTreeMap::new());
+    let source = cli.globals.source.clone().unwrap_or(".".into());
+    *QUIRKS
+        .lock()
+        .unwrap()
+        .entry(source)
+        .or_insert_with_key(|source| {
+            #[cfg(test)]
+            tests::DETECT_QUIRKS_CALLS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let cml = source.join("CMakeLists.txt");
+            let llvm = source.join("llvm");
+            let (has_cml, has_llvm) = (cml.is_file(), llvm.is_dir());
+            trace!("detect_quirks: {cml:?} is file: {has_cml}, {llvm:?} is dir: {has_llvm}");
+            if !has_cml && has_llvm {
+                Quirks::Llvm
+            } else {
+                Quirks::None
+            }
+        })
+}
+
+/// The CMake source directory, which for a detected (rather than explicitly selected) LLVM checkout
+/// is the "llvm" subdirectory of the given source, e.g. "-s llvm-project" configures
+/// "llvm-project/llvm". That subdirectory must contain a CMakeLists.txt, so that pointing at the
+/// wrong directory is an error rather than a confusing cmake failure.
+fn source_dir(cli: &Cli, profile: Profile) -> Result<PathBuf> {
+    let detected_llvm = cli.globals.project.is_none()
+        && cli.globals.quirks.is_none()
+        && matches!(detect_quirks(cli), Quirks::Llvm);
+    if !detected_llvm {
+        return Ok(cli.globals.source.clone().unwrap_or(profile.source.into()));
+    }
+    let root = cli.globals.source.clone().unwrap_or(".".into());
+    let llvm = root.join("llvm");
+    if !llvm.join("CMakeLists.txt").is_file() {
+        bail!(
+            "{root:?} looks like an LLVM checkout, as it has an llvm directory but no \
+             CMakeLists.txt, but {llvm:?} has no CMakeLists.txt either; use -s/--source to select \
+             the CMake source directory"
+        );
+    }
+    Ok(llvm)
+}
+
+/// Expand a leading "~" component of `path` to the home directory. Other forms, such as "~user"
+/// for the home directory of another user, are not supported, and are left as-is with a warning.
+fn expand_tilde(path: PathBuf) -> PathBuf {
+    let mut components = path.components();
+    match components.next() {
+        Some(Component::Normal(first)) if first == "~" => {}
+        Some(Component::Normal(first)) if first.to_string_lossy().starts_with('~') => {
+            warning!("not expanding {path:?}, as \"~user\" paths are not supported");
+            return path;
+        }
+        _ => return path,
+    }
+    match dirs::home_dir() {
+        Some(home) if components.as_path().as_os_str().is_empty() => home,
+        Some(home) => home.join(components),
+        None => path,
+    }
+}
+
+/// The `PATH` with the entry prepended by activate (for the executables in `bin_subdir`) removed,
+/// so the tools cm runs are not shadowed by those in the activated binary directory, or `None` if
+/// activate has not prepended it.
+fn adjusted_path(bin_subdir: &Path) -> Option<OsString> {
+    let (path, cm_bin) = (env::var_os("PATH")?, env::var_os("CM_BIN")?);
+    let mut entries = env::split_paths(&path);
+    if entries.next()? != activate_path_entry(cm_bin.as_ref(), bin_subdir) {
+        return None;
+    }
+    env::join_paths(entries).ok()
+}
+
+fn get_adjusted_path() -> Option<&'static OsStr> {
+    ADJUSTED_PATH.get().and_then(Option::as_deref)
+}
+
+/// Build the command which runs a --pre or --post hook.
+fn hook_cmd(hook: &str) -> process::Command {
+    let mut cmd = process::Command::new("sh");
+    cmd.arg("-c");
+    cmd.arg(hook);
+    cmd
+}
+
+fn adjust_path(mut cmd: process::Command) -> process::Command {
+    if let Some(adjusted_path) = get_adjusted_path() {
+        cmd.env("PATH", adjusted_path);
+    }
     cmd
 }
 
@@ -462,41 +2309,414 @@ fn quote<'a, S: Into<Quotable<'a>>>(s: S) -> OsString {
     out
 }
 
+/// Like `quote`, but for POSIX sh, which is what tools splitting `*_OPTS` variables (e.g. via
+/// Python's `shlex`) understand.
+fn quote_sh<'a, S: Into<Quotable<'a>>>(s: S) -> OsString {
+    let mut out = OsString::new();
+    Sh::quote_into(s.into(), &mut out);
+    out
+}
+
+/// Format a command as a shell command-line, for the dry-run output.
+fn format_cmd(cmd: &process::Command) -> String {
+    let mut quoted = Vec::new();
+    // There is no way to ask a Command whether it inherits the environment, but every planned
+    // command is cleared alike.
+    if isolate_env() {
+        quoted.extend(["env".to_owned(), "-i".to_owned()]);
+    }
+    quoted.extend(cmd.get_envs().filter_map(|(key, val)| {
+        Some(format!(
+            "{}={}",
+            quote(key).to_string_lossy(),
+            quote(val?).to_string_lossy(),
+        ))
+    }));
+    quoted.push(quote(cmd.get_program()).to_string_lossy().into_owned());
+    quoted.extend(
+        cmd.get_args()
+            .map(|arg| quote(arg).to_string_lossy().into_owned()),
+    );
+    quoted.join(" ")
+}
+
+/// Print the "cooked" args vector (less the binary name) to stderr, for debugging the config file.
+fn dump_args(args: &[OsString]) {
+    let quoted = args
+        .iter()
+        .skip(1)
+        .map(|arg| quote(arg.as_os_str()).to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    eprintln!("{}", quoted.join(" "));
+}
+
+/// Check the running cm against the version requirement in the nearest .cm-version file at or
+/// above `source`, warning on a mismatch (or failing, if `strict`).
+fn check_version_pin(source: &Path, strict: bool) -> Result<()> {
+    let Some(path) = source
+        .ancestors()
+        .map(|dir| dir.join(".cm-version"))
+        .find(|path| path.is_file())
+    else {
+        return Ok(());
+    };
+    let contents = fs::read_to_string(&path).with_context(|| format!("could not read {path:?}"))?;
+    let req = VersionReq::parse(contents.trim())
+        .with_context(|| format!("invalid version requirement in {path:?}"))?;
+    let version = Version::parse(env!("CARGO_PKG_VERSION")).expect("invalid cm package version");
+    debug!("version pin {req} from {path:?}, running {version}");
+    if !req.matches(&version) {
+        let msg = format!("cm {version} does not satisfy the version {req} required by {path:?}");
+        if strict {
+            bail!(msg);
+        }
+        warning!("{msg}");
+    }
+    Ok(())
+}
+
+/// Check the formatted `plan` against the expected plan in `path`, printing a diff to stderr and
+/// failing if they differ.
+fn check_plan(path: &Path, plan: &str) -> Result<()> {
+    let expected = fs::read_to_string(path)
+        .with_context(|| format!("could not read expected plan {path:?}"))?;
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = plan.lines().collect();
+    if expected == actual {
+        return Ok(());
+    }
+    eprintln!("--- {}", path.display());
+    eprintln!("+++ plan");
+    for line in diff_lines(&expected, &actual) {
+        eprintln!("{line}");
+    }
+    bail!("the plan does not match {path:?}");
+}
+
+/// A line-based diff of `old` and `new`, with each line prefixed by '-' (only in `old`), '+' (only
+/// in `new`), or ' ' (in both).
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<String> {
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut diff = vec![];
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push(format!(" {}", old[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(format!("-{}", old[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+/// Install a logger for developer-oriented diagnostics, configured by the CM_LOG environment
+/// variable (e.g. CM_LOG=debug). When it is unset no logger is installed and the log macros reduce
+/// to a check of the (disabled) maximum level.
+fn init_logging() {
+    if env::var_os("CM_LOG").is_some_and(|v| !v.is_empty()) {
+        env_logger::Builder::from_env(env_logger::Env::new().filter("CM_LOG"))
+            .format_timestamp(None)
+            .init();
+    }
+}
+
 pub fn cm() -> Result<()> {
-    let cli = Cli::parse_from(args::build()?);
-    let quirks = cli.globals.quirks.unwrap_or(detect_quirks(&cli));
-    let source = absolute(cli.globals.source.clone().unwrap_or(match quirks {
-        Quirks::None => ".".into(),
-        Quirks::Llvm => "llvm".into(),
-    }))?;
-    let binary = absolute(cli.globals.binary.clone().unwrap_or("build".into()))?;
+    init_logging();
+    let args = args::build()?;
+    debug!("cooked args: {args:?}");
+    if env::var_os("CM_DUMP_ARGS").is_some_and(|v| !v.is_empty()) {
+        dump_args(&args);
+    }
+    let mut cli = Cli::parse_from(args);
+    VERBOSITY.get_or_init(|| cli.globals.verbosity());
+    // Paths from the environment or a config file have not been through the shell, so may still
+    // have a leading "~".
+    cli.globals.source = cli.globals.source.map(expand_tilde);
+    cli.globals.binary = cli.globals.binary.map(expand_tilde);
+    OFFLINE.get_or_init(|| matches!(cli.globals.offline, Some(Bool(true))));
+    ISOLATE_ENV.get_or_init(|| matches!(cli.globals.isolate_env, Some(Bool(true))));
+    ADJUSTED_PATH.get_or_init(|| adjusted_path(cli.globals.bin_subdir()));
+    // Completions and manpages depend only on the Cli definition, not on any paths or quirks.
+    if let Command::Completions(ref c) = cli.command {
+        let mut script = Vec::new();
+        clap_complete::generate(c.shell, &mut Cli::command(), "cm", &mut script);
+        return io::stdout()
+            .write_all(&script)
+            .context("could not write completions");
+    }
+    // The prompt only reflects the environment activate set up, and is run for every prompt, so
+    // skips resolving any paths.
+    if let Command::Prompt(_) = cli.command {
+        if let Some(status) = prompt_status() {
+            println!("{status}");
+        }
+        return Ok(());
+    }
+    if let Command::Man(ref m) = cli.command {
+        // Building the command gives subcommands their full names (e.g. "cm-configure"), as for
+        // the manpages generated by build.rs.
+        let mut cmd = Cli::command();
+        cmd.build();
+        let cmd = match &m.subcommand {
+            None => cmd,
+            Some(name) => match cmd.find_subcommand(name) {
+                Some(subcmd) => subcmd.clone(),
+                None => bail!("no subcommand named {name:?}"),
+            },
+        };
+        let mut page = Vec::new();
+        render_man(cmd, &mut page).context("could not render manpage")?;
+        return io::stdout()
+            .write_all(&page)
+            .context("could not write manpage");
+    }
+    let profile = profile(cli.globals.project.unwrap_or_else(|| implied_project(&cli)));
+    let quirks = cli.globals.quirks.unwrap_or(profile.quirks);
+    let source = absolute(source_dir(&cli, profile)?)?;
+    let binary =
+        absolute(
+            cli.globals
+                .binary
+                .clone()
+                .unwrap_or_else(|| match cli.globals.per_config_dir {
+                    Some(Bool(true)) => format!("build-{}", cli.globals.final_config()).into(),
+                    _ => "build".into(),
+                }),
+        )?;
+    check_version_pin(
+        &source,
+        matches!(cli.globals.strict_version, Some(Bool(true))),
+    )?;
+    debug!(
+        "quirks: {}, source: {source:?}, binary: {binary:?}",
+        quirks.as_ref().to_string_lossy()
+    );
     let paths = Paths {
         source: &source,
         binary: &binary,
     };
-    let cmds = plan(&cli.command, &cli, quirks, paths)?;
+    let mut cmds = plan(&cli.command, &cli, quirks, paths)?;
+    if !cmds.is_empty() {
+        if let Some(pre) = &cli.globals.pre {
+            cmds.insert(0, hook_cmd(pre).into());
+        }
+        if let Some(post) = &cli.globals.post {
+            cmds.push(PlannedCommand::PostHook(hook_cmd(post)));
+        }
+    }
+    if let Some(path) = &cli.globals.env_file {
+        let vars = read_env_file(path)?;
+        for cmd in cmds.iter_mut() {
+            match cmd {
+                PlannedCommand::Spawn(cmd) | PlannedCommand::PostHook(cmd) => {
+                    add_env_file_vars(cmd, &vars)
+                }
+                PlannedCommand::Parallel { cmds, .. } => {
+                    for cmd in cmds.iter_mut() {
+                        add_env_file_vars(cmd, &vars);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    if isolate_env() {
+        for cmd in cmds.iter_mut() {
+            match cmd {
+                PlannedCommand::Spawn(cmd) | PlannedCommand::PostHook(cmd) => clear_env(cmd),
+                PlannedCommand::Parallel { cmds, .. } => cmds.iter_mut().for_each(clear_env),
+                _ => {}
+            }
+        }
+    }
+    for cmd in cmds.iter() {
+        debug!("planned: {}", cmd.format());
+    }
+    if let Some(Bool(true)) = cli.globals.dump_env {
+        let mut vars = BTreeSet::new();
+        for cmd in cmds.iter() {
+            let cmds = match cmd {
+                PlannedCommand::Spawn(cmd) | PlannedCommand::PostHook(cmd) => slice::from_ref(cmd),
+                PlannedCommand::Parallel { cmds, .. } => cmds.as_slice(),
+                _ => &[],
+            };
+            for (key, val) in cmds.iter().flat_map(process::Command::get_envs) {
+                if let Some(val) = val {
+                    let mut var = key.to_owned();
+                    var.push("=");
+                    var.push(val);
+                    vars.insert(var);
+                }
+            }
+        }
+        return PlannedCommand::Print(vars.into_iter().collect())
+            .run()
+            .map(|_| ());
+    }
+    if cli.globals.dry_run_out.is_some() || cli.globals.expect_plan.is_some() {
+        let mut out = String::new();
+        for cmd in cmds.iter() {
+            out.push_str(&cmd.format());
+            out.push('\n');
+        }
+        if let Some(path) = &cli.globals.dry_run_out {
+            fs::write(path, &out).with_context(|| format!("could not write {path:?}"))?;
+        }
+        if let Some(path) = &cli.globals.expect_plan {
+            check_plan(path, &out)?;
+        }
+        return Ok(());
+    }
+    let mut failure = None;
+    let keep_going = matches!(cli.globals.keep_going, Some(Bool(true)));
+    // The result of each step, for the --summary.
+    let mut results = vec![];
     for ref mut cmd in cmds {
         if let Some(Bool(true)) = cli.globals.dry_run {
-            let mut quoted = Vec::new();
-            quoted.extend(cmd.get_envs().filter_map(|(key, val)| {
-                Some(format!(
-                    "{}={}",
-                    quote(key).to_string_lossy(),
-                    quote(val?).to_string_lossy(),
-                ))
-            }));
-            quoted.push(quote(cmd.get_program()).to_string_lossy().into_owned());
-            quoted.extend(
-                cmd.get_args()
-                    .map(|arg| quote(arg).to_string_lossy().into_owned()),
-            );
-            println!("{}", quoted.join(" "));
-        } else {
-            let status = cmd.status()?;
+            println!("{}", cmd.format());
+            continue;
+        }
+        if failure.is_some() && !keep_going && !cmd.always_run() {
+            results.push(("SKIPPED", cmd.format()));
+            continue;
+        }
+        if let Some(Bool(true)) = cli.globals.print_plan {
+            println!("{}", cmd.format());
+        }
+        if verbosity() > 0 {
+            eprintln!("+ {}", cmd.format());
+        }
+        if let PlannedCommand::PostHook(hook) = cmd {
+            let status = match &failure {
+                Some(CommandFailedError(code)) => code.unwrap_or(1),
+                None => 0,
+            };
+            hook.env("CM_STATUS", status.to_string());
+        }
+        let mut result = "OK";
+        if let Some(status) = cmd.run()? {
             if !status.success() {
-                return Err(Error::new(CommandFailedError(status.code())));
+                failure.get_or_insert(CommandFailedError(status.code()));
+                result = "FAILED";
             }
         }
+        results.push((result, cmd.format()));
+    }
+    if matches!(cli.globals.summary, Some(Bool(true))) && !results.is_empty() && verbosity() >= 0 {
+        eprintln!("Summary:");
+        for (result, step) in results.iter() {
+            // Align any continuation lines of the step (e.g. of a parallel step) with the first.
+            let step = step.replace('\n', &format!("\n{:10}", ""));
+            eprintln!("  {result:<7} {step}");
+        }
+        match &failure {
+            Some(err) => eprintln!("Result: FAILED ({err})"),
+            None => eprintln!("Result: OK"),
+        }
+    }
+    match failure {
+        Some(err) => Err(Error::new(err)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// The number of times `detect_quirks` has checked the filesystem.
+    pub(super) static DETECT_QUIRKS_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn event(name: &str, start: u64, end: u64, tid: usize) -> serde_json::Value {
+        json!({
+            "name": name,
+            "cat": "targets",
+            "ph": "X",
+            "ts": start * 1000,
+            "dur": (end - start) * 1000,
+            "pid": 0,
+            "tid": tid,
+            "args": {},
+        })
+    }
+
+    #[test]
+    fn ninja_log_to_trace_uses_most_recent_build() {
+        let log = "# ninja log v5\n\
+                   0\t500\t0\told.o\t1\n\
+                   10\t50\t0\tb.o\tbb\n\
+                   60\t90\t0\tc.o\tcc\n\
+                   0\t100\t0\ta.o\taa\n\
+                   100\t200\t0\tlib.so\tdd\n\
+                   100\t200\t0\tlib.so.1\tdd\n";
+        assert_eq!(
+            ninja_log_to_trace(log).unwrap(),
+            json!([
+                event("a.o", 0, 100, 0),
+                event("b.o", 10, 50, 1),
+                event("c.o", 60, 90, 1),
+                event("lib.so, lib.so.1", 100, 200, 0),
+            ])
+        );
+    }
+
+    #[test]
+    fn ninja_log_to_trace_rejects_invalid_logs() {
+        assert!(ninja_log_to_trace("not a log\n").is_err());
+        assert!(ninja_log_to_trace("# ninja log v5\n0\t1\tfoo\n").is_err());
+        assert!(ninja_log_to_trace("# ninja log v5\nx\t1\t0\tfoo\th\n").is_err());
+    }
+
+    #[test]
+    fn diff_lines_marks_changed_lines() {
+        assert_eq!(
+            diff_lines(&["a", "b", "c"], &["a", "x", "c", "d"]),
+            [" a", "-b", "+x", " c", "+d"]
+        );
+        assert_eq!(diff_lines(&["a"], &["a"]), [" a"]);
+        assert_eq!(diff_lines(&["a", "b"], &[]), ["-a", "-b"]);
+    }
+
+    #[test]
+    fn ninja_log_to_trace_accepts_empty_log() {
+        assert_eq!(ninja_log_to_trace("# ninja log v7\n").unwrap(), json!([]));
+    }
+
+    #[test]
+    fn detect_quirks_checks_each_source_once() {
+        let cli = Cli::parse_from(["cm", "--source", "/nonexistent", "build"]);
+        let first = detect_quirks(&cli);
+        assert!(matches!(implied_project(&cli), Project::Generic));
+        assert!(source_dir(&cli, profile(Project::Generic)).is_ok());
+        assert!(matches!(first, Quirks::None));
+        assert_eq!(DETECT_QUIRKS_CALLS.load(Ordering::Relaxed), 1);
+        // A different source directory is checked afresh, rather than reusing the first result.
+        let checkout = env::temp_dir().join(format!("cm-detect-quirks-{}", process::id()));
+        fs::create_dir_all(checkout.join("llvm")).unwrap();
+        let mut args = vec![OsString::from("cm"), "--source".into()];
+        args.extend([checkout.clone().into(), "build".into()]);
+        let cli = Cli::parse_from(args);
+        let (second, third) = (detect_quirks(&cli), detect_quirks(&cli));
+        fs::remove_dir_all(&checkout).unwrap();
+        assert!(matches!(second, Quirks::Llvm));
+        assert!(matches!(third, Quirks::Llvm));
+        assert_eq!(DETECT_QUIRKS_CALLS.load(Ordering::Relaxed), 2);
     }
-    Ok(())
 }
@@ -0,0 +1,2 @@
+remove [CWD]/build/CMakeCache.txt [CWD]/build/CMakeFiles
+cmake -S [CWD] -B [CWD]/build -G Ninja $'-DCMAKE_BUILD_TYPE=RelWithDebInfo' $'-DBUILD_SHARED_LIBS=On' $'-DCMAKE_PREFIX_PATH=' $'-DCMAKE_INSTALL_PREFIX=dist' $'-DCMAKE_EXPORT_COMPILE_COMMANDS=On' $'-DCMAKE_C_COMPILER_LAUNCHER=ccache' $'-DCMAKE_CXX_COMPILER_LAUNCHER=ccache' $'-DCMAKE_C_FLAGS=-fcolor-diagnostics --user-c-flag' $'-DCMAKE_CXX_FLAGS=-fcolor-diagnostics --user-cxx-flag'